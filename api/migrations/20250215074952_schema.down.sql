@@ -0,0 +1,5 @@
+drop table weather;
+drop table photo;
+drop table note;
+drop table users;
+drop function update_modified_row;
@@ -0,0 +1,4 @@
+alter table photo
+  drop column caption,
+  drop column author_id,
+  drop column size_b;
@@ -0,0 +1,13 @@
+create table api_token (
+    id UUID default gen_random_uuid() primary key not null,
+    user_id UUID not null references users(id) on delete cascade,
+    name text not null,
+    prefix text not null,
+    token_hash text unique not null,
+    scopes text[] not null,
+    last_used_at timestamp with time zone,
+    revoked_at timestamp with time zone,
+    created_at timestamp with time zone default now() not null
+);
+
+create index api_token_user_id on api_token(user_id);
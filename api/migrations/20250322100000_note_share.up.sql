@@ -0,0 +1,14 @@
+create table note_share (
+    note_id UUID not null references note(id) on delete cascade,
+    user_id UUID not null references users(id) on delete cascade,
+    role text not null check (role in ('viewer', 'editor')),
+    created_at timestamp with time zone default now() not null,
+    updated_at timestamp with time zone default now() not null,
+    primary key (note_id, user_id)
+);
+
+create index note_share_user_id on note_share(user_id);
+
+create trigger update_note_share_updated_at
+  before update on note_share
+  for each row execute function update_modified_row();
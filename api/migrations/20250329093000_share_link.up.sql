@@ -0,0 +1,11 @@
+create table share_link (
+    id UUID default gen_random_uuid() primary key not null,
+    note_id UUID not null references note(id) on delete cascade,
+    token text unique not null,
+    password_hash text,
+    expires_at timestamp with time zone,
+    revoked_at timestamp with time zone,
+    created_at timestamp with time zone default now() not null
+);
+
+create index share_link_note_id on share_link(note_id);
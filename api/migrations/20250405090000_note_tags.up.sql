@@ -0,0 +1 @@
+alter table note add column tags text[] default '{}' not null;
@@ -0,0 +1,8 @@
+create table note_link (
+    source_id UUID not null references note(id) on delete cascade,
+    target_id UUID not null references note(id) on delete cascade,
+    created_at timestamp with time zone default now() not null,
+    primary key (source_id, target_id)
+);
+
+create index note_link_target_id on note_link(target_id);
@@ -0,0 +1,16 @@
+create table note_template (
+    id UUID default gen_random_uuid() primary key not null,
+    author_id UUID not null references users(id) on delete cascade,
+    name text not null,
+    title text not null,
+    content text not null,
+    tags text[] default '{}' not null,
+    created_at timestamp with time zone default now() not null,
+    updated_at timestamp with time zone default now() not null
+);
+
+create index note_template_author_id on note_template(author_id);
+
+create trigger update_note_template_updated_at
+  before update on note_template
+  for each row execute function update_modified_row();
@@ -0,0 +1,2 @@
+drop table journal;
+drop table user_location;
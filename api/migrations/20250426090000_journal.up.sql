@@ -0,0 +1,19 @@
+create table user_location (
+    user_id UUID primary key not null references users(id) on delete cascade,
+    lat double precision not null,
+    lon double precision not null,
+    unit text default 'C' not null,
+    created_at timestamp with time zone default now() not null,
+    updated_at timestamp with time zone default now() not null
+);
+
+create trigger update_user_location_updated_at
+  before update on user_location
+  for each row execute function update_modified_row();
+
+create table journal (
+    user_id UUID not null references users(id) on delete cascade,
+    date date not null,
+    note_id UUID unique not null references note(id) on delete cascade,
+    primary key (user_id, date)
+);
@@ -0,0 +1,11 @@
+create table attachment (
+    id UUID default gen_random_uuid() primary key not null,
+    note_id UUID not null references note(id) on delete cascade,
+    uploader_id UUID not null references users(id) on delete cascade,
+    file_name text not null,
+    content_type text not null,
+    size_b bigint not null,
+    created_at timestamp with time zone default now() not null
+);
+
+create index attachment_note_id on attachment(note_id);
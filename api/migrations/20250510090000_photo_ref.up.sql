@@ -0,0 +1,8 @@
+create table photo_ref (
+    note_id UUID not null references note(id) on delete cascade,
+    photo_name text not null references photo(name) on delete cascade,
+    created_at timestamp with time zone default now() not null,
+    primary key (note_id, photo_name)
+);
+
+create index photo_ref_photo_name on photo_ref(photo_name);
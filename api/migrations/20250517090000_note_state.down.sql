@@ -0,0 +1,4 @@
+alter table note
+  drop column pinned,
+  drop column favorite,
+  drop column archived;
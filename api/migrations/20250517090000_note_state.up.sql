@@ -0,0 +1,4 @@
+alter table note
+  add column pinned boolean default false not null,
+  add column favorite boolean default false not null,
+  add column archived boolean default false not null;
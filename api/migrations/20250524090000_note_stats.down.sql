@@ -0,0 +1,7 @@
+drop table writing_day;
+
+alter table note
+  drop column word_count,
+  drop column char_count,
+  drop column reading_minutes,
+  drop column outline;
@@ -0,0 +1,12 @@
+alter table note
+  add column word_count integer default 0 not null,
+  add column char_count integer default 0 not null,
+  add column reading_minutes integer default 0 not null,
+  add column outline text[] default '{}' not null;
+
+create table writing_day (
+    user_id UUID not null references users(id) on delete cascade,
+    date date not null,
+    words integer default 0 not null,
+    primary key (user_id, date)
+);
@@ -0,0 +1 @@
+alter table note drop column version;
@@ -0,0 +1 @@
+alter table note add column version integer default 1 not null;
@@ -0,0 +1 @@
+alter table note_link drop column by_title;
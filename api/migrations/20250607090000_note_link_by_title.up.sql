@@ -0,0 +1,10 @@
+alter table note_link add column by_title boolean default false not null;
+
+-- Links saved before this were either kind. Wiki links only resolve within
+-- one author's notes, so those are the ones that could have been by title.
+update note_link set by_title = true
+from note source, note target
+where source.id = note_link.source_id
+  and target.id = note_link.target_id
+  and source.author_id = target.author_id
+  and strpos(lower(source.content), '[[' || lower(target.title)) > 0;
@@ -0,0 +1 @@
+alter table photo drop column referenced_at;
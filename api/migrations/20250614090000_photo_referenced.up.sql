@@ -0,0 +1,6 @@
+-- Set once a note embeds the photo. Only photos that were embedded and aren't
+-- any more are collected, so uploads kept just for the gallery stay.
+alter table photo add column referenced_at timestamp with time zone;
+
+update photo set referenced_at = now()
+where exists (select 1 from photo_ref where photo_ref.photo_name = photo.name);
@@ -0,0 +1,5 @@
+drop trigger update_note_updated_at on note;
+
+create trigger update_note_updated_at
+  before update on note
+  for each row execute function update_modified_row();
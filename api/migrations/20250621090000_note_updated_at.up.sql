@@ -0,0 +1,7 @@
+-- Pinning, favoriting, archiving and recounting stats don't change what a
+-- note says, so they shouldn't move it in lists ordered by updated_at.
+drop trigger update_note_updated_at on note;
+
+create trigger update_note_updated_at
+  before update of title, content, tags on note
+  for each row execute function update_modified_row();
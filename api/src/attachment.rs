@@ -0,0 +1,450 @@
+//! Files of any kind attached to a note, such as PDFs, text or archives.
+//! Unlike photos they are always downloaded rather than shown inline, and go
+//! when their note does.
+
+use axum::{
+    extract::{Multipart, Path, State},
+    http::{
+        header::{
+            CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE,
+            X_CONTENT_TYPE_OPTIONS,
+        },
+        StatusCode,
+    },
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, query_scalar, PgPool};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    share::{self, Access},
+    storage::Storage,
+    AppState,
+};
+
+/// Largest file that can be attached.
+pub const MAX_ATTACHMENT_BYTES: usize = 25 * 1024 * 1024;
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Attachment {
+    id: Uuid,
+    note_id: Uuid,
+    uploader_id: Uuid,
+    file_name: String,
+    /// Detected from the file itself, not taken from the upload.
+    content_type: String,
+    size_b: i64,
+    created_at: DateTime<Utc>,
+}
+
+/// Multipart body for `upload`. Only used to document the endpoint.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct AttachmentUpload {
+    #[schema(format = Binary, content_media_type = "application/octet-stream")]
+    file: String,
+}
+
+fn key(id: Uuid) -> String {
+    format!("attachments/{}", id)
+}
+
+fn not_found() -> AppError {
+    AppError::WithStatus(
+        StatusCode::NOT_FOUND,
+        anyhow::Error::msg("No such attachment"),
+    )
+}
+
+/// The last component of an uploaded file's path, as browsers may send one.
+fn clean_name(name: Option<&str>) -> String {
+    let name = name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    if name.is_empty() {
+        "attachment".to_owned()
+    } else {
+        name
+    }
+}
+
+/// The type of a file from its first bytes, or its extension when those
+/// don't tell. Never a type a browser would render as a page.
+fn detect(file_name: &str, bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"Rar!\x1a\x07", "application/vnd.rar"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+    ];
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    let signature = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+        .map(|(_, content_type)| *content_type);
+    let text = !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok();
+    match (signature, extension.as_str()) {
+        // Office documents are zip archives inside.
+        (Some("application/zip"), "docx") => {
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        }
+        (Some("application/zip"), "xlsx") => {
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        }
+        (Some("application/zip"), "pptx") => {
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        }
+        (Some(content_type), _) => content_type,
+        (None, "csv") if text => "text/csv; charset=utf-8",
+        (None, "md" | "markdown") if text => "text/markdown; charset=utf-8",
+        (None, "json") if text => "application/json",
+        (None, _) if text => "text/plain; charset=utf-8",
+        (None, _) => "application/octet-stream",
+    }
+}
+
+/// Ids of the note's attachments, to remove their files once it's deleted.
+pub async fn for_note(db: &PgPool, note_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+    let ids = query_scalar!("select id from attachment where note_id = $1", note_id)
+        .fetch_all(db)
+        .await?;
+    Ok(ids)
+}
+
+/// Removes attachment files from the bucket. Failures are logged and left
+/// behind, as the attachments themselves are already gone.
+pub async fn remove_files(storage: &Storage, ids: &[Uuid]) {
+    for id in ids {
+        if let Err(error) = storage.delete(&key(*id)).await {
+            tracing::warn!("Couldn't remove attachment {}: {}", id, error);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/attachments",
+    tag = "attachments",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, body = Vec<Attachment>)),
+    security(("clerk" = []))
+)]
+pub async fn get_all(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<Attachment>> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let attachments = query_as!(
+        Attachment,
+        "select * from attachment where note_id = $1 order by created_at",
+        id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(attachments))
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/attachments",
+    tag = "attachments",
+    params(("id" = Uuid, Path, description = "Note id")),
+    request_body(content = AttachmentUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = Attachment),
+        (status = 400, description = "No file"),
+        (status = 413, description = "File is too large")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn upload(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    mut multipart: Multipart,
+) -> JsonRes<Attachment> {
+    share::require(&app.db, id, user.id, Access::Editor).await?;
+    let Some(file) = multipart.next_field().await? else {
+        return Err(AppError::WithStatus(
+            StatusCode::BAD_REQUEST,
+            anyhow::Error::msg("Expected a file"),
+        ));
+    };
+    let file_name = clean_name(file.file_name());
+    let bytes = file.bytes().await?;
+    if bytes.len() > MAX_ATTACHMENT_BYTES {
+        return Err(AppError::WithStatus(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            anyhow::Error::msg("File is too large"),
+        ));
+    }
+    let content_type = detect(&file_name, &bytes);
+    let size_b = bytes.len() as i64;
+    let attachment_id = Uuid::new_v4();
+    app.storage
+        .put(&key(attachment_id), bytes, Some(content_type))
+        .await?;
+    let inserted = query_as!(
+        Attachment,
+        "insert into attachment (id, note_id, uploader_id, file_name, content_type, size_b)
+        values ($1, $2, $3, $4, $5, $6) returning *",
+        attachment_id,
+        id,
+        user.id,
+        file_name,
+        content_type,
+        size_b
+    )
+    .fetch_one(&app.db)
+    .await;
+    match inserted {
+        Ok(attachment) => Ok(Json(attachment)),
+        Err(error) => {
+            // Nothing else would ever find the object to delete it.
+            remove_files(&app.storage, &[attachment_id]).await;
+            Err(error.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/attachments/{attachment_id}",
+    tag = "attachments",
+    params(
+        ("id" = Uuid, Path, description = "Note id"),
+        ("attachment_id" = Uuid, Path, description = "Attachment id")
+    ),
+    responses(
+        (status = 200, description = "The file, as a download"),
+        (status = 404, description = "No such attachment")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn download(
+    Path((id, attachment_id)): Path<(Uuid, Uuid)>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<impl IntoResponse, AppError> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let attachment = query_as!(
+        Attachment,
+        "select * from attachment where id = $1 and note_id = $2",
+        attachment_id,
+        id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    let object = app.storage.get(&key(attachment.id)).await?;
+    // A plain ASCII name for old clients, and the real one for the rest.
+    let fallback: String = attachment
+        .file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let disposition = format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(&attachment.file_name, NON_ALPHANUMERIC)
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, attachment.content_type),
+            (CONTENT_LENGTH, object.bytes.len().to_string()),
+            (CONTENT_DISPOSITION, disposition),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            (CACHE_CONTROL, "private, max-age=3600".to_owned()),
+        ],
+        object.bytes,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/note/{id}/attachments/{attachment_id}",
+    tag = "attachments",
+    params(
+        ("id" = Uuid, Path, description = "Note id"),
+        ("attachment_id" = Uuid, Path, description = "Attachment id")
+    ),
+    responses(
+        (status = 200, description = "The deleted attachment", body = Attachment),
+        (status = 404, description = "No such attachment")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn delete(
+    Path((id, attachment_id)): Path<(Uuid, Uuid)>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Attachment> {
+    share::require(&app.db, id, user.id, Access::Editor).await?;
+    let attachment = query_as!(
+        Attachment,
+        "delete from attachment where id = $1 and note_id = $2 returning *",
+        attachment_id,
+        id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    remove_files(&app.storage, &[attachment.id]).await;
+    Ok(Json(attachment))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    };
+    use serde_json::json;
+
+    use super::{clean_name, detect, Attachment};
+    use crate::{
+        note::Note,
+        storage::Storage,
+        testing::{body, json, TestApp},
+    };
+
+    #[test]
+    fn detects_content_types() {
+        assert_eq!(detect("x.bin", b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(
+            detect("report.docx", b"PK\x03\x04rest"),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        );
+        assert_eq!(detect("x.zip", b"PK\x03\x04rest"), "application/zip");
+        assert_eq!(detect("data.csv", b"a,b\n1,2"), "text/csv; charset=utf-8");
+        assert_eq!(
+            detect("page.html", b"<script>x</script>"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(detect("x.txt", b"\x00\x01\xff"), "application/octet-stream");
+        assert_eq!(clean_name(Some("C:\\Users\\a\\notes.txt")), "notes.txt");
+        assert_eq!(clean_name(None), "attachment");
+    }
+
+    fn stored(app: &TestApp) -> usize {
+        match &app.state.storage {
+            Storage::Memory(objects) => objects.lock().unwrap().len(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn attaches_and_downloads_files() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Docs", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+        let uri = format!("/note/{}/attachments", note.id);
+
+        let res = app
+            .post_file(&uri, "user_a", "Résumé.pdf", "image/png", b"%PDF-1.4 body")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let attachment: Attachment = json(res).await;
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(attachment.size_b, 13);
+
+        let res = app
+            .post_file(&uri, "user_b", "x.txt", "text/plain", b"x")
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = app
+            .get(&format!("{}/{}", uri, attachment.id), "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/pdf");
+        assert_eq!(
+            res.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"R_sum_.pdf\"; filename*=UTF-8''R%C3%A9sum%C3%A9%2Epdf"
+        );
+        assert_eq!(&body(res).await[..], b"%PDF-1.4 body");
+
+        let attachments: Vec<Attachment> = json(app.get(&uri, "user_a").await).await;
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(stored(&app), 1);
+
+        app.delete(&format!("/note/{}", note.id), "user_a").await;
+        assert_eq!(stored(&app), 0);
+    }
+
+    #[tokio::test]
+    async fn removes_the_file_when_saving_fails() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Docs", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+        sqlx::query("alter table attachment add constraint fail check (size_b < 0) not valid")
+            .execute(&app.state.db)
+            .await
+            .unwrap();
+        let res = app
+            .post_file(
+                &format!("/note/{}/attachments", note.id),
+                "user_a",
+                "a.txt",
+                "text/plain",
+                b"hello",
+            )
+            .await;
+        assert_ne!(res.status(), StatusCode::OK);
+        assert_eq!(stored(&app), 0);
+    }
+}
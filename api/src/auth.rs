@@ -0,0 +1,338 @@
+//! Protected routes accept either a Clerk session or a personal access token.
+//! `authenticate` works out which one a request carries and records it as an
+//! [`Identity`]; handlers then take [`CurrentUser`] to get the `User` behind it.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clerk_rs::validators::{
+    authorizer::{ClerkAuthorizer, ClerkError},
+    axum::AxumClerkRequest,
+    jwks::MemoryCacheJwksProvider,
+};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use ts_rs::TS;
+use utoipa::ToSchema;
+
+use crate::{
+    clerk::{find_user, User},
+    error::AppError,
+    token::{self, TOKEN_PREFIX},
+    AppState,
+};
+
+/// How Clerk sessions on protected routes are verified.
+#[derive(Clone)]
+pub enum Auth {
+    Clerk(ClerkAuthorizer<MemoryCacheJwksProvider>),
+    /// Trusts `Authorization: Bearer <clerk id>` without verification.
+    #[cfg(test)]
+    Fake,
+}
+
+/// What a personal access token may do. Write access implies read access to
+/// the same resource.
+#[derive(TS)]
+#[ts(export)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema, Display, EnumString)]
+pub enum Scope {
+    #[serde(rename = "notes:read")]
+    #[strum(serialize = "notes:read")]
+    NotesRead,
+    #[serde(rename = "notes:write")]
+    #[strum(serialize = "notes:write")]
+    NotesWrite,
+    #[serde(rename = "photos:read")]
+    #[strum(serialize = "photos:read")]
+    PhotosRead,
+    #[serde(rename = "photos:write")]
+    #[strum(serialize = "photos:write")]
+    PhotosWrite,
+    #[serde(rename = "weather:read")]
+    #[strum(serialize = "weather:read")]
+    WeatherRead,
+}
+
+impl Scope {
+    fn grants(self, required: Scope) -> bool {
+        self == required
+            || matches!(
+                (self, required),
+                (Scope::NotesWrite, Scope::NotesRead) | (Scope::PhotosWrite, Scope::PhotosRead)
+            )
+    }
+}
+
+/// The scope a token needs for a route, or `None` for routes only a signed in
+/// user may call, such as managing tokens.
+fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    // Fetching the daily journal note makes it when it doesn't exist yet.
+    let write = !matches!(*method, Method::GET | Method::HEAD) || path == "/notes/daily";
+    let resource = path.trim_start_matches('/').split('/').next()?;
+    match (resource, write) {
+        ("notes" | "note" | "templates" | "template", false) => Some(Scope::NotesRead),
+        ("notes" | "note" | "templates" | "template", true) => Some(Scope::NotesWrite),
+        ("photos", false) => Some(Scope::PhotosRead),
+        ("photos", true) => Some(Scope::PhotosWrite),
+        ("weather", false) => Some(Scope::WeatherRead),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub enum Identity {
+    Session { clerk_id: String },
+    Token { user: User, scopes: Vec<Scope> },
+}
+
+impl Identity {
+    pub fn clerk_id(&self) -> &str {
+        match self {
+            Identity::Session { clerk_id } => clerk_id,
+            Identity::Token { user, .. } => &user.clerk_id,
+        }
+    }
+
+    fn allows(&self, method: &Method, path: &str) -> bool {
+        match self {
+            Identity::Session { .. } => true,
+            Identity::Token { scopes, .. } => required_scope(method, path)
+                .is_some_and(|required| scopes.iter().any(|scope| scope.grants(required))),
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> AppError {
+    AppError::WithStatus(
+        StatusCode::UNAUTHORIZED,
+        anyhow::Error::msg(message.to_owned()),
+    )
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+async fn identify(app: &AppState, headers: &HeaderMap) -> Result<Identity, AppError> {
+    let bearer = bearer(headers);
+    if let Some(secret) = bearer.filter(|secret| secret.starts_with(TOKEN_PREFIX)) {
+        let (user, scopes) = token::resolve(&app.db, secret)
+            .await?
+            .ok_or_else(|| unauthorized("Invalid or revoked token"))?;
+        return Ok(Identity::Token { user, scopes });
+    }
+    match &app.auth {
+        Auth::Clerk(authorizer) => {
+            let request = AxumClerkRequest {
+                headers: headers.clone(),
+            };
+            match authorizer.authorize(&request).await {
+                Ok(jwt) => Ok(Identity::Session { clerk_id: jwt.sub }),
+                Err(ClerkError::Unauthorized(message)) => Err(unauthorized(&message)),
+                Err(ClerkError::InternalServerError(message)) => {
+                    Err(AppError::Internal(anyhow::Error::msg(message)))
+                }
+            }
+        }
+        #[cfg(test)]
+        Auth::Fake => bearer
+            .map(|sub| Identity::Session {
+                clerk_id: sub.to_owned(),
+            })
+            .ok_or_else(|| unauthorized("Missing bearer token")),
+    }
+}
+
+pub async fn authenticate(
+    State(app): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let identity = match identify(&app, request.headers()).await {
+        Ok(identity) => identity,
+        Err(error) => return error.into_response(),
+    };
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    if !identity.allows(request.method(), path) {
+        return AppError::WithStatus(
+            StatusCode::FORBIDDEN,
+            anyhow::Error::msg("Token is missing the required scope"),
+        )
+        .into_response();
+    }
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
+
+const USER_TTL: Duration = Duration::from_secs(30);
+
+/// Users looked up by Clerk id, so a session doesn't cost a query on every
+/// request. The Clerk webhook invalidates entries when a user changes.
+pub struct UserCache {
+    users: Mutex<HashMap<String, (User, Instant)>>,
+    ttl: Duration,
+}
+
+impl Default for UserCache {
+    fn default() -> Self {
+        UserCache {
+            users: Mutex::default(),
+            ttl: USER_TTL,
+        }
+    }
+}
+
+impl UserCache {
+    pub fn get(&self, clerk_id: &str) -> Option<User> {
+        let users = self.users.lock().unwrap();
+        users
+            .get(clerk_id)
+            .filter(|(_, cached_at)| cached_at.elapsed() < self.ttl)
+            .map(|(user, _)| user.clone())
+    }
+
+    fn insert(&self, user: User) {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|_, (_, cached_at)| cached_at.elapsed() < self.ttl);
+        users.insert(user.clerk_id.clone(), (user, Instant::now()));
+    }
+
+    pub fn invalidate(&self, clerk_id: &str) {
+        self.users.lock().unwrap().remove(clerk_id);
+    }
+}
+
+/// The user making the request, however they signed in. Rejects with 401 when
+/// the request isn't authenticated and 403 when a valid Clerk session has no
+/// user here yet, e.g. before the `user.created` webhook has arrived.
+pub struct CurrentUser(pub User);
+
+#[async_trait]
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, app: &AppState) -> Result<Self, AppError> {
+        let clerk_id = match parts.extensions.get::<Identity>() {
+            Some(Identity::Token { user, .. }) => return Ok(CurrentUser(user.clone())),
+            Some(Identity::Session { clerk_id }) => clerk_id,
+            None => return Err(unauthorized("Not signed in")),
+        };
+        if let Some(user) = app.users.get(clerk_id) {
+            return Ok(CurrentUser(user));
+        }
+        let user = find_user(&app.db, clerk_id).await?.ok_or_else(|| {
+            AppError::WithStatus(
+                StatusCode::FORBIDDEN,
+                anyhow::Error::msg("No account for this session"),
+            )
+        })?;
+        app.users.insert(user.clone());
+        Ok(CurrentUser(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{Method, StatusCode};
+
+    use super::{required_scope, Identity, Scope, UserCache};
+    use crate::{clerk::User, testing::TestApp};
+
+    fn user() -> User {
+        User {
+            id: Default::default(),
+            clerk_id: "user_a".to_owned(),
+            username: "a".to_owned(),
+            email: "a@example.com".to_owned(),
+            first_name: "A".to_owned(),
+            last_name: "A".to_owned(),
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    fn token(scopes: Vec<Scope>) -> Identity {
+        Identity::Token {
+            user: user(),
+            scopes,
+        }
+    }
+
+    #[test]
+    fn scopes_follow_resource_and_method() {
+        assert_eq!(
+            required_scope(&Method::GET, "/note/:id"),
+            Some(Scope::NotesRead)
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/note/:id"),
+            Some(Scope::NotesWrite)
+        );
+        assert_eq!(
+            required_scope(&Method::POST, "/photos"),
+            Some(Scope::PhotosWrite)
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/notes/daily"),
+            Some(Scope::NotesWrite)
+        );
+        assert_eq!(required_scope(&Method::GET, "/tokens"), None);
+    }
+
+    #[test]
+    fn write_implies_read() {
+        let identity = token(vec![Scope::NotesWrite]);
+        assert!(identity.allows(&Method::GET, "/notes"));
+        assert!(identity.allows(&Method::POST, "/notes"));
+        assert!(!identity.allows(&Method::GET, "/photos"));
+        assert!(!identity.allows(&Method::GET, "/tokens"));
+
+        let identity = token(vec![Scope::NotesRead]);
+        assert!(!identity.allows(&Method::POST, "/note/:id"));
+    }
+
+    #[test]
+    fn cached_users_expire() {
+        let cache = UserCache::default();
+        cache.insert(user());
+        assert!(cache.get("user_a").is_some());
+        cache.invalidate("user_a");
+        assert!(cache.get("user_a").is_none());
+
+        let cache = UserCache {
+            ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        cache.insert(user());
+        assert!(cache.get("user_a").is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_users() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let res = app.get("/notes", "user_unknown").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -9,12 +9,14 @@ use hmac::Hmac;
 use hmac::Mac;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use sqlx::{query_as, PgPool};
+use sqlx::{query, query_as, PgPool};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 type HmacSha256 = Hmac<Sha256>;
 
 use crate::{
+    config::WebhookSecret,
     error::{AppError, JsonRes},
     AppState,
 };
@@ -39,10 +41,10 @@ struct ClerkUser {
 pub struct ClerkWebhook {
     #[serde(alias = "type")]
     webhook_type: String,
-    data: ClerkUser,
+    data: serde_json::Value,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct User {
     pub id: Uuid,
     pub clerk_id: String,
@@ -54,68 +56,99 @@ pub struct User {
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct WebhookResponse {
     webhook_type: String,
     webhook_status: String,
 }
 
-fn verify_signature(header: &HeaderMap, body: &String) -> Result<(), AppError> {
-    let svix_secret =
-        BASE64_STANDARD.decode(env_var!("SVIX_SECRET").split("_").collect::<Vec<&str>>()[1])?;
-    let svix_id = header.get("svix-id");
-    let svix_timestamp = header.get("svix-timestamp");
-    let svix_signature = header.get("svix-signature");
-    let (Some(svix_id), Some(svix_timestamp), Some(svix_signature)) =
-        (svix_id, svix_timestamp, svix_signature)
-    else {
-        return Err(AppError::WithStatus(
-            StatusCode::BAD_REQUEST,
-            anyhow::Error::msg("Missing svix header".to_string()),
-        ));
-    };
-    let svix_signature = svix_signature.to_str()?.split(",").collect::<Vec<&str>>()[1];
-    let mac = {
-        let mut mac = HmacSha256::new_from_slice(&svix_secret)?;
-        mac.update(
-            format!(
-                "{}.{}.{}",
-                svix_id.to_str()?,
-                svix_timestamp.to_str()?,
-                body
-            )
-            .as_bytes(),
-        );
-        let mac = mac.finalize().into_bytes();
-        BASE64_STANDARD.encode(mac)
+fn bad_request(message: &str) -> AppError {
+    AppError::WithStatus(
+        StatusCode::BAD_REQUEST,
+        anyhow::Error::msg(message.to_owned()),
+    )
+}
+
+/// Checks the body against the `svix-signature` header, a space separated
+/// list of `v1,<base64 signature>` entries, any of which may match.
+fn verify_signature(
+    secret: &WebhookSecret,
+    header: &HeaderMap,
+    body: &str,
+) -> Result<(), AppError> {
+    let value = |name: &str| {
+        header
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| bad_request("Missing svix header"))
     };
-    if mac != svix_signature {
-        return Err(AppError::WithStatus(
-            StatusCode::BAD_REQUEST,
-            anyhow::Error::msg("Invalid signature!".to_string()),
-        ));
+    let svix_id = value("svix-id")?;
+    let svix_timestamp = value("svix-timestamp")?;
+    let svix_signature = value("svix-signature")?;
+    let mut mac = HmacSha256::new_from_slice(secret.key())?;
+    mac.update(format!("{}.{}.{}", svix_id, svix_timestamp, body).as_bytes());
+    let signatures: Vec<Vec<u8>> = svix_signature
+        .split_whitespace()
+        .filter_map(|entry| entry.split_once(','))
+        .filter(|(version, _)| *version == "v1")
+        .filter_map(|(_, signature)| BASE64_STANDARD.decode(signature).ok())
+        .collect();
+    if signatures.is_empty() {
+        return Err(bad_request("Malformed svix-signature header"));
+    }
+    if !signatures
+        .iter()
+        .any(|signature| mac.clone().verify_slice(signature).is_ok())
+    {
+        return Err(bad_request("Invalid signature!"));
     }
     Ok(())
 }
 
+#[utoipa::path(
+    post,
+    path = "/clerk-webhook",
+    tag = "clerk",
+    request_body(content = String, description = "Svix signed Clerk event", content_type = "application/json"),
+    responses(
+        (status = 200, description = "Handled, or ignored when the event isn't about a user", body = WebhookResponse),
+        (status = 400, description = "Bad signature or malformed event")
+    )
+)]
 pub async fn post_webhook(
     header: HeaderMap,
     State(app): State<AppState>,
     body: String,
 ) -> JsonRes<WebhookResponse> {
-    verify_signature(&header, &body)?;
-    let webhook: ClerkWebhook = serde_json::from_str(&body)?;
-    let allow_webhook = webhook.webhook_type == "user.created";
-    if allow_webhook {
-        let ClerkUser {
-            id,
-            first_name,
-            last_name,
-            username,
-            email_addresses,
-            created_at,
-            updated_at,
-        } = webhook.data;
+    verify_signature(&app.config.svix_secret, &header, &body)?;
+    let webhook: ClerkWebhook =
+        serde_json::from_str(&body).map_err(|_| bad_request("Malformed event"))?;
+    if !matches!(
+        webhook.webhook_type.as_str(),
+        "user.created" | "user.updated"
+    ) {
+        // Clerk sends whatever the endpoint is subscribed to, and retries
+        // anything but a 2xx, so other events are acknowledged and dropped.
+        return Ok(Json(WebhookResponse {
+            webhook_type: webhook.webhook_type,
+            webhook_status: "Ignored".to_owned(),
+        }));
+    }
+    let ClerkUser {
+        id,
+        first_name,
+        last_name,
+        username,
+        email_addresses,
+        created_at,
+        updated_at,
+    } = serde_json::from_value(webhook.data).map_err(|_| bad_request("Malformed user"))?;
+    let email = email_addresses
+        .first()
+        .ok_or_else(|| bad_request("User has no email address"))?
+        .email_address
+        .as_str();
+    let webhook_status = if webhook.webhook_type == "user.created" {
         query_as!(
             User,
             "insert into users (
@@ -128,7 +161,7 @@ pub async fn post_webhook(
             updated_at
         ) values ($1, $2, $3, $4, $5, $6, $7)",
             id,
-            email_addresses[0].email_address,
+            email,
             username,
             first_name,
             last_name,
@@ -137,25 +170,191 @@ pub async fn post_webhook(
         )
         .execute(&app.db)
         .await?;
-        Ok(Json(WebhookResponse {
-            webhook_type: webhook.webhook_type,
-            webhook_status: "User created!".to_owned(),
-        }))
+        "User created!"
     } else {
-        Err(AppError::WithStatus(
-            StatusCode::BAD_REQUEST,
-            anyhow::Error::msg("Webhook is misconfigured".to_string()),
-        ))
-    }
+        query!(
+            "update users set
+            email = $2,
+            username = $3,
+            first_name = $4,
+            last_name = $5,
+            updated_at = $6
+        where clerk_id = $1",
+            id,
+            email,
+            username,
+            first_name,
+            last_name,
+            DateTime::from_timestamp_millis(updated_at),
+        )
+        .execute(&app.db)
+        .await?;
+        "User updated!"
+    };
+    app.users.invalidate(&id);
+    Ok(Json(WebhookResponse {
+        webhook_type: webhook.webhook_type,
+        webhook_status: webhook_status.to_owned(),
+    }))
 }
 
-pub async fn get_user(db: &PgPool, clerk_id: &str) -> Result<User, AppError> {
+pub async fn find_user(db: &PgPool, clerk_id: &str) -> Result<Option<User>, AppError> {
     let user = query_as!(
         User,
         "select id, clerk_id, email, username, first_name, last_name, created_at, updated_at from users where clerk_id = $1",
         clerk_id
     )
-    .fetch_one(db)
+    .fetch_optional(db)
     .await?;
     Ok(user)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use base64::prelude::*;
+    use hmac::Mac;
+    use serde_json::json;
+
+    use super::{find_user, HmacSha256};
+    use crate::{config::WebhookSecret, testing::TestApp};
+
+    fn signed(secret: &WebhookSecret, body: &str, valid: bool) -> Request<Body> {
+        let mut mac = HmacSha256::new_from_slice(secret.key()).unwrap();
+        mac.update(format!("msg_1.1700000000.{}", body).as_bytes());
+        let mut signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+        if !valid {
+            signature = signature.chars().rev().collect();
+        }
+        Request::post("/clerk-webhook")
+            .header("svix-id", "msg_1")
+            .header("svix-timestamp", "1700000000")
+            .header("svix-signature", format!("v1,{}", signature))
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    fn user_event(webhook_type: &str, first_name: &str) -> String {
+        json!({
+            "type": webhook_type,
+            "data": {
+                "id": "user_new",
+                "username": "newbie",
+                "email_addresses": [{ "email_address": "newbie@example.com" }],
+                "first_name": first_name,
+                "last_name": "User",
+                "created_at": 1700000000000i64,
+                "updated_at": 1700000000000i64
+            }
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn creates_user() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let body = user_event("user.created", "New");
+        let res = app
+            .send(signed(&app.state.config.svix_secret, &body, true))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let user = find_user(&app.state.db, "user_new")
+            .await
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email, "newbie@example.com");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signature() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let body = user_event("user.created", "New");
+        let res = app
+            .send(signed(&app.state.config.svix_secret, &body, false))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(find_user(&app.state.db, "user_new")
+            .await
+            .ok()
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_signature_headers() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let body = user_event("user.created", "New");
+        for header in ["", "v1", "v1,not base64!", "v2,c2ln"] {
+            let mut request = signed(&app.state.config.svix_secret, &body, true);
+            request
+                .headers_mut()
+                .insert("svix-signature", header.parse().unwrap());
+            let res = app.send(request).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_other_events_and_rejects_users_without_email() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let secret = &app.state.config.svix_secret;
+        let body = json!({ "type": "session.created", "data": { "id": "sess_1" } }).to_string();
+        let res = app.send(signed(secret, &body, true)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut event: serde_json::Value =
+            serde_json::from_str(&user_event("user.created", "New")).unwrap();
+        event["data"]["email_addresses"] = json!([]);
+        let res = app.send(signed(secret, &event.to_string(), true)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(find_user(&app.state.db, "user_new")
+            .await
+            .ok()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn validates_secrets() {
+        assert!("whsec_dGVzdA==".parse::<WebhookSecret>().is_ok());
+        assert!("dGVzdA==".parse::<WebhookSecret>().is_err());
+        assert!("whsec_!!".parse::<WebhookSecret>().is_err());
+        assert!("whsec_".parse::<WebhookSecret>().is_err());
+    }
+
+    #[tokio::test]
+    async fn updates_drop_cached_user() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let secret = &app.state.config.svix_secret;
+        app.send(signed(secret, &user_event("user.created", "New"), true))
+            .await;
+        assert_eq!(app.get("/notes", "user_new").await.status(), StatusCode::OK);
+        assert!(app.state.users.get("user_new").is_some());
+
+        let res = app
+            .send(signed(secret, &user_event("user.updated", "Renamed"), true))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(app.state.users.get("user_new").is_none());
+        let user = find_user(&app.state.db, "user_new")
+            .await
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.first_name, "Renamed");
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP server. This is the default when no command is given.
+    Serve {
+        /// Apply pending migrations before accepting requests.
+        #[arg(long)]
+        migrate: bool,
+    },
+    /// Manage the database schema.
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+    /// Work out the stored word counts and outlines of every note again, e.g.
+    /// for notes saved before they were kept.
+    RecountNotes,
+    /// Write the TypeScript bindings for the API types.
+    ExportBindings {
+        #[arg(long, default_value = "bindings")]
+        out_dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply all pending migrations.
+    Up,
+    /// Revert the latest migration, or every migration newer than `--target`.
+    Down {
+        #[arg(long)]
+        target: Option<i64>,
+    },
+    /// List migrations and whether they have been applied.
+    Status,
+}
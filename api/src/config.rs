@@ -0,0 +1,275 @@
+use std::{
+    env,
+    fmt::{self, Debug, Display},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use base64::prelude::*;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Clone)]
+pub struct Config {
+    pub bind_address: String,
+    pub shutdown_timeout_secs: u64,
+    pub database_url: String,
+    pub auto_migrate: bool,
+    pub db_max_connections: u32,
+    pub clerk_secret_key: String,
+    pub svix_secret: WebhookSecret,
+    pub s3_url: String,
+    pub s3_bucket: String,
+    pub r2_access_key_id: String,
+    pub r2_access_key_secret: String,
+    pub allow_origin: Vec<HeaderValue>,
+    pub loc_token: String,
+    pub weather_api_url: String,
+    pub geocoding_api_url: String,
+    /// Bearer token Prometheus scrapes `/metrics` with. Metrics aren't served
+    /// when it's empty.
+    pub metrics_token: String,
+    /// Reverse proxies whose `X-Forwarded-For` is trusted to name the client.
+    /// Requests from anywhere else are limited by their peer address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Delete photos notes stopped embedding once a day. Off unless set, as
+    /// deleting is for good.
+    pub collect_photos: bool,
+}
+
+/// Shown in place of secrets when the config is logged.
+const REDACTED: &str = "<redacted>";
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("bind_address", &self.bind_address)
+            .field("shutdown_timeout_secs", &self.shutdown_timeout_secs)
+            .field("database_url", &REDACTED)
+            .field("auto_migrate", &self.auto_migrate)
+            .field("db_max_connections", &self.db_max_connections)
+            .field("clerk_secret_key", &REDACTED)
+            .field("svix_secret", &REDACTED)
+            .field("s3_url", &self.s3_url)
+            .field("s3_bucket", &self.s3_bucket)
+            .field("r2_access_key_id", &self.r2_access_key_id)
+            .field("r2_access_key_secret", &REDACTED)
+            .field("allow_origin", &self.allow_origin)
+            .field("loc_token", &REDACTED)
+            .field("weather_api_url", &self.weather_api_url)
+            .field("geocoding_api_url", &self.geocoding_api_url)
+            .field("metrics_token", &REDACTED)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("collect_photos", &self.collect_photos)
+            .finish()
+    }
+}
+
+/// The key Svix signs webhooks with, configured as `whsec_` followed by the
+/// key in base64.
+#[derive(Clone, Default)]
+pub struct WebhookSecret(Vec<u8>);
+
+impl WebhookSecret {
+    pub fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for WebhookSecret {
+    type Err = String;
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        let key = secret
+            .strip_prefix("whsec_")
+            .ok_or("expected a secret starting with whsec_")?;
+        let key = BASE64_STANDARD
+            .decode(key)
+            .map_err(|error| format!("invalid base64 after whsec_: {}", error))?;
+        if key.is_empty() {
+            return Err("the secret is empty".to_owned());
+        }
+        Ok(WebhookSecret(key))
+    }
+}
+
+impl Config {
+    /// Reads every key from the environment, falling back to the TOML file at
+    /// `CONFIG_FILE` (or `config.toml` when present). Env vars are the upper
+    /// case form of the TOML keys, e.g. `database_url` -> `DATABASE_URL`.
+    pub fn load() -> Result<Self> {
+        let mut src = Source::open()?;
+        let config = Config {
+            bind_address: src.optional("bind_address", "0.0.0.0:8080".to_owned()),
+            shutdown_timeout_secs: src.optional("shutdown_timeout_secs", 30),
+            database_url: src.required("database_url"),
+            auto_migrate: src.optional("auto_migrate", false),
+            db_max_connections: src.optional("db_max_connections", 5),
+            clerk_secret_key: src.required("clerk_secret_key"),
+            svix_secret: src.required("svix_secret"),
+            s3_url: src.required("s3_url"),
+            s3_bucket: src.optional("s3_bucket", "editor".to_owned()),
+            r2_access_key_id: src.required("r2_access_key_id"),
+            r2_access_key_secret: src.required("r2_access_key_secret"),
+            allow_origin: src.list("allow_origin"),
+            loc_token: src.required("loc_token"),
+            weather_api_url: src
+                .optional("weather_api_url", "https://api.open-meteo.com".to_owned()),
+            geocoding_api_url: src
+                .optional("geocoding_api_url", "https://us1.locationiq.com".to_owned()),
+            metrics_token: src.optional("metrics_token", String::new()),
+            trusted_proxies: src.optional_list("trusted_proxies"),
+            collect_photos: src.optional("collect_photos", false),
+        };
+        src.finish()?;
+        Ok(config)
+    }
+
+    /// Loads only the database url, for commands that don't serve requests.
+    pub fn database_url() -> Result<String> {
+        let mut src = Source::open()?;
+        let database_url = src.required("database_url");
+        src.finish()?;
+        Ok(database_url)
+    }
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let table = text
+        .parse::<toml::Table>()
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+    Ok(table)
+}
+
+struct Source {
+    file: toml::Table,
+    errors: Vec<String>,
+}
+
+impl Source {
+    fn open() -> Result<Self> {
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) => read_table(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_table(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            Err(_) => toml::Table::new(),
+        };
+        Ok(Source {
+            file,
+            errors: Vec::new(),
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.errors.is_empty() {
+            anyhow::bail!("Invalid configuration:\n  {}", self.errors.join("\n  "));
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, key: &str) -> Option<String> {
+        env::var(key.to_uppercase())
+            .ok()
+            .or_else(|| match self.file.get(key)? {
+                toml::Value::String(s) => Some(s.clone()),
+                toml::Value::Array(values) => Some(
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map(|s| s.to_owned()).unwrap_or(v.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                value => Some(value.to_string()),
+            })
+    }
+
+    fn parse<T>(&mut self, key: &str, value: String) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("{}: {}", key.to_uppercase(), e));
+                None
+            }
+        }
+    }
+
+    fn required<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        match self.lookup(key) {
+            Some(value) => self.parse(key, value).unwrap_or_default(),
+            None => {
+                self.errors
+                    .push(format!("{} is not set", key.to_uppercase()));
+                T::default()
+            }
+        }
+    }
+
+    fn list<T>(&mut self, key: &str) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.required::<String>(key);
+        self.split(key, value)
+    }
+
+    fn optional_list<T>(&mut self, key: &str) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.optional(key, String::new());
+        self.split(key, value)
+    }
+
+    fn split<T>(&mut self, key: &str, value: String) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        value
+            .split(",")
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.parse(key, s.to_owned()))
+            .collect()
+    }
+
+    fn optional<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.lookup(key) {
+            Some(value) => self.parse(key, value).unwrap_or(default),
+            None => default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::config;
+
+    #[test]
+    fn redacts_secrets() {
+        let config = config("postgres://user:hunter2@db/editor", "http://stub");
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("database_url: \"<redacted>\""));
+    }
+}
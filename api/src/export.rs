@@ -0,0 +1,346 @@
+//! Downloads of a user's notes as a zip archive, to back them up or move them
+//! to another app.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Cursor, Write},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::query_as;
+use tokio::task::spawn_blocking;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    auth::CurrentUser,
+    error::AppError,
+    note::Note,
+    photo,
+    render::{markdown_to_html, page, photo_name, photo_refs, photo_src, rewrite_images},
+    AppState,
+};
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Html,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ExportQuery {
+    /// Markdown files with YAML front matter, or standalone HTML pages.
+    #[serde(default)]
+    format: ExportFormat,
+    /// Also bundle the photos the notes embed. Markdown files link to them in
+    /// `photos/`, HTML pages get them inlined.
+    #[serde(default)]
+    photos: bool,
+    /// Comma separated ids of the notes to export, all of them if left out.
+    ids: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FrontMatter<'a> {
+    title: &'a str,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    tags: &'a [String],
+}
+
+fn parse_ids(ids: &str) -> Result<Vec<Uuid>, AppError> {
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            Uuid::parse_str(id).map_err(|_| {
+                AppError::WithStatus(
+                    StatusCode::BAD_REQUEST,
+                    anyhow::Error::msg(format!("Invalid note id: {}", id)),
+                )
+            })
+        })
+        .collect()
+}
+
+/// A file name for the note from its title, unique among `taken`.
+fn file_stem(note: &Note, taken: &mut HashSet<String>) -> String {
+    let slug = note
+        .title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let base = if slug.is_empty() {
+        note.id.to_string()
+    } else {
+        slug
+    };
+    let mut stem = base.clone();
+    let mut n = 1;
+    while !taken.insert(stem.clone()) {
+        n += 1;
+        stem = format!("{}-{}", base, n);
+    }
+    stem
+}
+
+fn to_markdown(note: &Note, exported: &HashSet<String>) -> Result<String, AppError> {
+    let front_matter = serde_yaml::to_string(&FrontMatter {
+        title: &note.title,
+        created: note.created_at,
+        updated: note.updated_at,
+        tags: &note.tags,
+    })?;
+    let content = rewrite_images(&note.content, |src| {
+        photo_name(src)
+            .filter(|name| exported.contains(name))
+            .map(|name| format!("photos/{}", photo_src(&name)))
+    });
+    Ok(format!("---\n{}---\n\n{}", front_matter, content))
+}
+
+fn archive(files: Vec<(String, Vec<u8>, CompressionMethod)>) -> Result<Vec<u8>, AppError> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, bytes, compression) in files {
+        zip.start_file(
+            name,
+            SimpleFileOptions::default().compression_method(compression),
+        )?;
+        zip.write_all(&bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[utoipa::path(
+    get,
+    path = "/notes/export",
+    tag = "notes",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "A zip archive of the notes", content_type = "application/zip"),
+        (status = 400, description = "Malformed note ids")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn notes(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let ids = query.ids.as_deref().map(parse_ids).transpose()?;
+    let notes = query_as!(
+        Note,
+        "select * from note
+        where author_id = $1 and ($2::uuid[] is null or id = any($2))
+        order by created_at",
+        user.id,
+        ids.as_deref()
+    )
+    .fetch_all(&app.db)
+    .await?;
+
+    let mut photos = Vec::new();
+    if query.photos {
+        let mut names = Vec::new();
+        for note in &notes {
+            names.extend(photo_refs(&note.content));
+        }
+        photos = photo::load(&app, user.id, &names).await?;
+        // Names are checked when photos are stored, but older ones weren't,
+        // and they become paths in the archive.
+        photos.retain(|(name, _)| photo::valid_name(name));
+    }
+
+    let mut files = Vec::new();
+    let mut taken = HashSet::new();
+    match query.format {
+        ExportFormat::Markdown => {
+            let exported: HashSet<String> = photos.iter().map(|(name, _)| name.clone()).collect();
+            for note in &notes {
+                files.push((
+                    format!("{}.md", file_stem(note, &mut taken)),
+                    to_markdown(note, &exported)?.into_bytes(),
+                    CompressionMethod::Deflated,
+                ));
+            }
+            for (name, object) in photos {
+                files.push((
+                    format!("photos/{}", name),
+                    object.bytes.to_vec(),
+                    CompressionMethod::Stored,
+                ));
+            }
+        }
+        ExportFormat::Html => {
+            let inlined: HashMap<String, String> = photos
+                .iter()
+                .map(|(name, object)| (name.clone(), photo::data_uri(object)))
+                .collect();
+            for note in &notes {
+                let html = page(&note.title, &markdown_to_html(&note.content, &inlined));
+                files.push((
+                    format!("{}.html", file_stem(note, &mut taken)),
+                    html.into_bytes(),
+                    CompressionMethod::Deflated,
+                ));
+            }
+        }
+    }
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/zip"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"notes.zip\""),
+        ],
+        spawn_blocking(move || archive(files)).await??,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use axum::http::{header::CONTENT_TYPE, StatusCode};
+    use serde_json::json;
+    use sqlx::query;
+    use zip::ZipArchive;
+
+    use crate::{
+        note::Note,
+        photo,
+        testing::{body, json, TestApp},
+    };
+
+    async fn export(app: &TestApp, uri: &str) -> ZipArchive<Cursor<Vec<u8>>> {
+        let res = app.get(uri, "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/zip");
+        ZipArchive::new(Cursor::new(body(res).await.to_vec())).unwrap()
+    }
+
+    fn read(zip: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> String {
+        let mut contents = String::new();
+        zip.by_name(name)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn exports_markdown_with_photos() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.post_file("/photos", "user_a", "cat one.png", "image/png", b"png")
+            .await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Trip: Day 1", "content": "![cat](cat%20one.png)", "tags": ["travel"] }),
+            )
+            .await,
+        )
+        .await;
+        app.post_json(
+            "/notes",
+            "user_a",
+            json!({ "title": "Trip: Day 1", "content": "Again" }),
+        )
+        .await;
+
+        let mut zip = export(&app, "/notes/export?photos=true").await;
+        assert_eq!(zip.len(), 3);
+        let markdown = read(&mut zip, "trip-day-1.md");
+        assert!(markdown.starts_with("---\ntitle: 'Trip: Day 1'\n"));
+        assert!(markdown.contains("tags:\n- travel\n---\n\n![cat](photos/cat%20one.png)"));
+        assert_eq!(
+            read(&mut zip, "trip-day-1-2.md").lines().last(),
+            Some("Again")
+        );
+        assert_eq!(read(&mut zip, "photos/cat one.png"), "png");
+
+        let mut zip = export(&app, &format!("/notes/export?ids={}", note.id)).await;
+        assert_eq!(zip.len(), 1);
+        assert!(read(&mut zip, "trip-day-1.md").contains("![cat](cat%20one.png)"));
+    }
+
+    #[tokio::test]
+    async fn leaves_out_photos_named_like_paths() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let author = app.create_user("user_a").await;
+        // Stored before names were checked.
+        query!(
+            "insert into photo (name, caption, author_id, size_b) values ('../evil.png', '', $1, 3)",
+            author
+        )
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+        app.state
+            .storage
+            .put(&photo::key(author, "../evil.png"), "png".into(), None)
+            .await
+            .unwrap();
+        app.post_json(
+            "/notes",
+            "user_a",
+            json!({ "title": "Evil", "content": "![](../evil.png)" }),
+        )
+        .await;
+
+        let mut zip = export(&app, "/notes/export?photos=true").await;
+        assert_eq!(zip.len(), 1);
+        assert!(read(&mut zip, "evil.md").ends_with("![](../evil.png)"));
+    }
+
+    #[tokio::test]
+    async fn exports_html() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        app.post_file("/photos", "user_a", "cat.png", "image/png", b"png")
+            .await;
+        app.post_json(
+            "/notes",
+            "user_a",
+            json!({ "title": "Cat", "content": "# Cat\n\n![cat](cat.png)" }),
+        )
+        .await;
+        app.post_json(
+            "/notes",
+            "user_b",
+            json!({ "title": "Not mine", "content": "" }),
+        )
+        .await;
+
+        let mut zip = export(&app, "/notes/export?format=html&photos=true").await;
+        assert_eq!(zip.len(), 1);
+        let html = read(&mut zip, "cat.html");
+        assert!(html.contains("<title>Cat</title>"));
+        assert!(html.contains(r#"src="data:image/png;base64,cG5n""#));
+
+        let res = app.get("/notes/export?ids=nope", "user_a").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
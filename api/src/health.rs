@@ -0,0 +1,139 @@
+use std::{future::Future, time::Duration};
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{query, query_scalar, PgPool};
+use utoipa::ToSchema;
+
+use crate::{migrate::MIGRATOR, AppState};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    database: Check,
+    bucket: Check,
+    migrations: Check,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    build_time: Option<DateTime<Utc>>,
+    schema_version: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The process is alive", body = String))
+)]
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, description = "A dependency is unavailable", body = Readiness)
+    )
+)]
+pub async fn readyz(State(app): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let database = check(async {
+        query("select 1").execute(&app.db).await?;
+        Ok(())
+    });
+    let bucket = check(app.storage.check());
+    let migrations = check(async {
+        let applied = applied_migrations(&app.db).await?;
+        let pending = MIGRATOR
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+            .count();
+        if pending > 0 {
+            anyhow::bail!("{} pending migration(s)", pending);
+        }
+        Ok(())
+    });
+    let (database, bucket, migrations) = tokio::join!(database, bucket, migrations);
+    let ready = database.ok && bucket.ok && migrations.ok;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            database,
+            bucket,
+            migrations,
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "health",
+    responses((status = 200, body = Version))
+)]
+pub async fn version(State(app): State<AppState>) -> Json<Version> {
+    let schema_version = applied_migrations(&app.db)
+        .await
+        .ok()
+        .and_then(|applied| applied.last().copied());
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        build_time: env!("BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        schema_version,
+    })
+}
+
+async fn check(fut: impl Future<Output = anyhow::Result<()>>) -> Check {
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::Error::msg("Timed out")),
+    };
+    match result {
+        Ok(()) => Check {
+            ok: true,
+            error: None,
+        },
+        Err(e) => Check {
+            ok: false,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+async fn applied_migrations(db: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    let exists = query_scalar::<_, bool>("select to_regclass('_sqlx_migrations') is not null")
+        .fetch_one(db)
+        .await?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+    query_scalar("select version from _sqlx_migrations where success order by version")
+        .fetch_all(db)
+        .await
+}
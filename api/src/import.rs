@@ -0,0 +1,680 @@
+//! Imports a zip of Markdown files, as exported from here, from an Obsidian
+//! vault or from Notion. Front matter supplies titles, dates and tags, links
+//! between the files become links between the new notes and embedded images
+//! become photos.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Read},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Multipart, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use sqlx::{query, query_scalar};
+use tokio::task::spawn_blocking;
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    photo, photo_ref,
+    render::{photo_src, references, replace_spans},
+    stats::NoteStats,
+    token::random_string,
+    wikilink, AppState,
+};
+
+/// Largest archive accepted, before unpacking.
+pub const MAX_ARCHIVE_BYTES: usize = 64 * 1024 * 1024;
+const MAX_FILE_BYTES: u64 = 16 * 1024 * 1024;
+const MAX_UNPACKED_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportReport {
+    notes: Vec<ImportedNote>,
+    photos: Vec<ImportedPhoto>,
+    /// Files that were skipped, and why.
+    failures: Vec<ImportFailure>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportedNote {
+    path: String,
+    id: Uuid,
+    title: String,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportedPhoto {
+    path: String,
+    name: String,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportFailure {
+    path: String,
+    error: String,
+}
+
+/// Multipart body for `notes`. Only used to document the endpoint.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct ImportUpload {
+    #[schema(format = Binary, content_media_type = "application/zip")]
+    file: String,
+}
+
+fn bad_request(message: String) -> AppError {
+    AppError::WithStatus(StatusCode::BAD_REQUEST, anyhow::Error::msg(message))
+}
+
+fn is_markdown(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".md") || path.ends_with(".markdown")
+}
+
+fn image_type(path: &str) -> Option<&'static str> {
+    let (_, extension) = path.rsplit_once('.')?;
+    match extension.to_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        _ => None,
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// `relative` resolved against the directory `base` is in, or `None` if it
+/// climbs out of the archive.
+fn join(base: &str, relative: &str) -> Option<String> {
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in relative.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// The Markdown files and images in the archive by path. Anything else is
+/// reported as a failure, apart from hidden files such as `.obsidian/`.
+fn unpack(
+    bytes: &[u8],
+    failures: &mut Vec<ImportFailure>,
+) -> Result<BTreeMap<String, Vec<u8>>, AppError> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))
+        .map_err(|error| bad_request(format!("Not a zip archive: {}", error)))?;
+    let mut files = BTreeMap::new();
+    let mut unpacked = 0;
+    for i in 0..zip.len() {
+        let file = zip
+            .by_index(i)
+            .map_err(|error| bad_request(format!("Corrupt zip archive: {}", error)))?;
+        let path = file.name().trim_start_matches('/').to_owned();
+        if file.is_dir()
+            || path
+                .split('/')
+                .any(|part| part.starts_with('.') || part == "__MACOSX")
+        {
+            continue;
+        }
+        let mut fail = |error: &str| {
+            failures.push(ImportFailure {
+                path: path.clone(),
+                error: error.to_owned(),
+            })
+        };
+        if !is_markdown(&path) && image_type(&path).is_none() {
+            fail("Not a Markdown file or a supported image");
+            continue;
+        }
+        if path.split('/').any(|part| part == "..") {
+            fail("Path leaves the archive");
+            continue;
+        }
+        let mut bytes = Vec::new();
+        if file
+            .take(MAX_FILE_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .is_err()
+        {
+            fail("Couldn't be unpacked");
+            continue;
+        }
+        if bytes.len() as u64 > MAX_FILE_BYTES {
+            fail("File is too large");
+            continue;
+        }
+        unpacked += bytes.len() as u64;
+        if unpacked > MAX_UNPACKED_BYTES {
+            return Err(bad_request("Archive is too large once unpacked".to_owned()));
+        }
+        files.insert(path, bytes);
+    }
+    Ok(files)
+}
+
+/// Splits YAML front matter delimited by `---` lines off the body.
+fn split_front_matter(text: &str) -> (Option<&str>, &str) {
+    let Some(rest) = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))
+    else {
+        return (None, text);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            let body = rest[offset + line.len()..].trim_start_matches(['\r', '\n']);
+            return (Some(&rest[..offset]), body);
+        }
+        offset += line.len();
+    }
+    (None, text)
+}
+
+fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })
+        .map(|date| date.and_utc())
+}
+
+/// The first of `keys` holding a date, under the names different apps use.
+fn date(front_matter: &Mapping, keys: &[&str]) -> Option<DateTime<Utc>> {
+    keys.iter()
+        .find_map(|key| front_matter.get(*key)?.as_str().and_then(parse_date))
+}
+
+/// Tags as a list, or as one string separated by commas or spaces. Obsidian
+/// allows a leading `#`.
+fn tags(front_matter: &Mapping) -> Vec<String> {
+    let tags = match front_matter.get("tags").or_else(|| front_matter.get("tag")) {
+        Some(Value::Sequence(tags)) => tags
+            .iter()
+            .filter_map(|tag| match tag {
+                Value::String(tag) => Some(tag.clone()),
+                Value::Number(tag) => Some(tag.to_string()),
+                _ => None,
+            })
+            .collect(),
+        Some(Value::String(tags)) => tags
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .map(str::to_owned)
+            .collect(),
+        _ => Vec::new(),
+    };
+    tags.into_iter()
+        .map(|tag| tag.trim().trim_start_matches('#').to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// The file name without its extension, and without the id Notion appends to
+/// every page.
+fn title_from_path(path: &str) -> String {
+    let name = file_name(path);
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    match stem.rsplit_once(' ') {
+        Some((title, id)) if id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) => {
+            title.to_owned()
+        }
+        _ => stem.to_owned(),
+    }
+}
+
+struct ParsedNote {
+    path: String,
+    id: Uuid,
+    title: String,
+    body: String,
+    tags: Vec<String>,
+    created: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+}
+
+fn parse_note(path: &str, bytes: &[u8]) -> Result<ParsedNote, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "Not valid UTF-8".to_owned())?;
+    let text = text.trim_start_matches('\u{feff}');
+    let (front_matter, body) = split_front_matter(text);
+    let front_matter = match front_matter {
+        Some(yaml) => match serde_yaml::from_str::<Value>(yaml) {
+            Ok(Value::Mapping(mapping)) => mapping,
+            Ok(_) => Mapping::new(),
+            Err(error) => return Err(format!("Invalid front matter: {}", error)),
+        },
+        None => Mapping::new(),
+    };
+    let title = front_matter
+        .get("title")
+        .and_then(Value::as_str)
+        .map(str::to_owned)
+        .unwrap_or_else(|| title_from_path(path));
+    Ok(ParsedNote {
+        path: path.to_owned(),
+        id: Uuid::new_v4(),
+        title,
+        body: body.to_owned(),
+        tags: tags(&front_matter),
+        created: date(&front_matter, &["created", "created_at", "date"]),
+        updated: date(&front_matter, &["updated", "updated_at", "modified"]),
+    })
+}
+
+/// Where the files of the archive ended up.
+struct Imported<'a> {
+    files: &'a BTreeMap<String, Vec<u8>>,
+    /// Notes by path, and by title and file name in lower case.
+    notes: HashMap<String, Uuid>,
+    /// Images by path, once uploaded, or `None` if uploading failed.
+    photos: HashMap<String, Option<String>>,
+}
+
+impl Imported<'_> {
+    fn note(&self, from: &str, dest: &str, wiki: bool) -> Option<Uuid> {
+        if wiki {
+            let target = dest.split('#').next()?.trim();
+            let target = target.strip_suffix(".md").unwrap_or(target);
+            return self.notes.get(&file_name(target).to_lowercase()).copied();
+        }
+        if dest.contains(':') {
+            return None;
+        }
+        let dest = dest.split('#').next()?;
+        let path = join(from, &percent_decode_str(dest).decode_utf8_lossy())?;
+        self.notes.get(&path).copied()
+    }
+
+    /// The path of the image `dest` points at. Wiki embeds name a file
+    /// anywhere in the vault.
+    fn image(&self, from: &str, dest: &str, wiki: bool) -> Option<String> {
+        if dest.contains(':') {
+            return None;
+        }
+        let dest = percent_decode_str(dest).decode_utf8_lossy();
+        let path = join(from, &dest).filter(|path| self.files.contains_key(path));
+        path.or_else(|| {
+            let name = file_name(&dest);
+            self.files
+                .keys()
+                .filter(|path| image_type(path).is_some())
+                .find(|path| (wiki || !dest.contains('/')) && file_name(path) == name)
+                .cloned()
+        })
+    }
+}
+
+/// A photo name for `path` that isn't taken. Photos this user already has
+/// with the same name and size are assumed to be the same image, so
+/// importing an export again doesn't duplicate them.
+async fn photo_name(
+    app: &AppState,
+    author_id: Uuid,
+    path: &str,
+    size_b: i64,
+) -> Result<(String, bool), AppError> {
+    let name = file_name(path).to_owned();
+    let existing = query!("select author_id, size_b from photo where name = $1", name)
+        .fetch_optional(&app.db)
+        .await?;
+    Ok(match existing {
+        None => (name, false),
+        Some(photo) if photo.author_id == author_id && photo.size_b == size_b => (name, true),
+        Some(_) => (format!("{}-{}", random_string(8), name), false),
+    })
+}
+
+async fn upload(
+    app: &AppState,
+    author_id: Uuid,
+    path: &str,
+    bytes: &[u8],
+) -> Result<String, AppError> {
+    let (name, exists) = photo_name(app, author_id, path, bytes.len() as i64).await?;
+    if !exists {
+        let content_type = image_type(path).unwrap_or("application/octet-stream");
+        photo::store(
+            app,
+            author_id,
+            &name,
+            Bytes::copy_from_slice(bytes),
+            content_type,
+        )
+        .await?;
+    }
+    Ok(name)
+}
+
+#[utoipa::path(
+    post,
+    path = "/notes/import",
+    tag = "notes",
+    request_body(content = ImportUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "What was imported, and which files failed", body = ImportReport),
+        (status = 400, description = "No file, or not a zip archive")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn notes(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    mut multipart: Multipart,
+) -> JsonRes<ImportReport> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(bad_request("Expected a zip file".to_owned()));
+    };
+    let archive = field.bytes().await?;
+
+    // Inflating up to MAX_UNPACKED_BYTES would hold up other requests.
+    let (files, failures) = spawn_blocking(move || {
+        let mut failures = Vec::new();
+        unpack(&archive, &mut failures).map(|files| (files, failures))
+    })
+    .await??;
+    let mut report = ImportReport {
+        notes: Vec::new(),
+        photos: Vec::new(),
+        failures,
+    };
+    let mut parsed = Vec::new();
+    for (path, bytes) in files.iter().filter(|(path, _)| is_markdown(path)) {
+        match parse_note(path, bytes) {
+            Ok(note) => parsed.push(note),
+            Err(error) => report.failures.push(ImportFailure {
+                path: path.clone(),
+                error,
+            }),
+        }
+    }
+
+    let mut imported = Imported {
+        files: &files,
+        notes: HashMap::new(),
+        photos: HashMap::new(),
+    };
+    for note in &parsed {
+        imported.notes.insert(note.path.clone(), note.id);
+        let stem = title_from_path(&note.path).to_lowercase();
+        imported.notes.entry(stem).or_insert(note.id);
+        let title = note.title.to_lowercase();
+        imported.notes.entry(title).or_insert(note.id);
+    }
+
+    let mut saved = Vec::new();
+    for note in parsed {
+        let mut replacements = Vec::new();
+        for reference in references(&note.body) {
+            let dest = &*reference.dest;
+            if reference.image {
+                let Some(path) = imported.image(&note.path, dest, reference.wiki) else {
+                    continue;
+                };
+                if !imported.photos.contains_key(&path) {
+                    let name = match upload(&app, user.id, &path, &files[&path]).await {
+                        Ok(name) => {
+                            report.photos.push(ImportedPhoto {
+                                path: path.clone(),
+                                name: name.clone(),
+                            });
+                            Some(name)
+                        }
+                        Err(AppError::WithStatus(_, error) | AppError::Internal(error)) => {
+                            report.failures.push(ImportFailure {
+                                path: path.clone(),
+                                error: format!("Couldn't upload the image: {}", error),
+                            });
+                            None
+                        }
+                    };
+                    imported.photos.insert(path.clone(), name);
+                }
+                let Some(name) = &imported.photos[&path] else {
+                    continue;
+                };
+                let src = photo_src(name);
+                let text = if reference.wiki {
+                    format!("![]({})", src)
+                } else {
+                    src
+                };
+                replacements.push((reference.span, text));
+            } else if let Some(id) = imported.note(&note.path, dest, reference.wiki) {
+                let href = format!("/note/{}", id);
+                let text = if reference.wiki {
+                    format!("[{}]({})", reference.label.unwrap_or(dest), href)
+                } else {
+                    href
+                };
+                replacements.push((reference.span, text));
+            }
+        }
+        let content = replace_spans(&note.body, replacements);
+
+        let stats = NoteStats::of(&content);
+        let inserted = query_scalar!(
+            "insert into note (id, title, content, tags, author_id, created_at, updated_at,
+                word_count, char_count, reading_minutes, outline)
+            values ($1, $2, $3, $4, $5, coalesce($6, now()), coalesce($7, $6, now()),
+                $8, $9, $10, $11)
+            returning id",
+            note.id,
+            note.title,
+            content,
+            &note.tags,
+            user.id,
+            note.created,
+            note.updated,
+            stats.word_count,
+            stats.char_count,
+            stats.reading_minutes,
+            &stats.outline
+        )
+        .fetch_one(&app.db)
+        .await;
+        match inserted {
+            Ok(id) => {
+                saved.push((id, content));
+                report.notes.push(ImportedNote {
+                    path: note.path,
+                    id,
+                    title: note.title,
+                });
+            }
+            Err(error) => report.failures.push(ImportFailure {
+                path: note.path,
+                error: format!("Couldn't save the note: {}", error),
+            }),
+        }
+    }
+    // Links are recorded once every note exists to link to.
+    for (id, content) in saved {
+        wikilink::sync(&app.db, id, user.id, &content).await?;
+        photo_ref::sync(&app.db, id, user.id, &content).await?;
+    }
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use axum::http::StatusCode;
+    use chrono::{TimeZone, Utc};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::{parse_note, title_from_path, ImportReport};
+    use crate::{
+        note::Note,
+        photo::Photo,
+        stats::WritingStats,
+        testing::{json, TestApp},
+    };
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, bytes) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn reads_front_matter() {
+        let note = parse_note(
+            "vault/trip.md",
+            b"---\ntitle: Trip\ndate: 2024-01-02\nmodified: 2024-01-03T10:00:00Z\ntags: [travel, '#family']\n---\n\nBody",
+        )
+        .unwrap();
+        assert_eq!(note.title, "Trip");
+        assert_eq!(note.body, "Body");
+        assert_eq!(note.tags, ["travel", "family"]);
+        assert_eq!(
+            note.created,
+            Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).single()
+        );
+        assert_eq!(
+            note.updated,
+            Utc.with_ymd_and_hms(2024, 1, 3, 10, 0, 0).single()
+        );
+
+        let note = parse_note(
+            "Page 0123456789abcdef0123456789abcdef.md",
+            b"No front matter",
+        )
+        .unwrap();
+        assert_eq!(note.title, "Page");
+        assert_eq!(note.body, "No front matter");
+        assert!(note.created.is_none());
+
+        assert!(parse_note("bad.md", b"---\ntitle: [\n---\n").is_err());
+        assert!(parse_note("bad.md", b"\xff").is_err());
+        assert_eq!(title_from_path("a/b/Plain name.markdown"), "Plain name");
+    }
+
+    #[tokio::test]
+    async fn imports_a_vault() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let author = app.create_user("user_a").await;
+        let archive = zip(&[
+            ("vault/.obsidian/app.json", b"{}"),
+            (
+                "vault/Home.md",
+                b"---\ntitle: Home\ncreated: 2024-01-02T03:04:05Z\ntags: [a]\n---\nSee [[Trip|my trip]], [[Missing]] and [trip](Travel/Trip.md).\n\n![[cat.png]]",
+            ),
+            ("vault/Travel/Trip.md", b"![dog](../img/dog%201.png)"),
+            ("vault/img/cat.png", b"cat"),
+            ("vault/img/dog 1.png", b"dog"),
+            ("vault/Broken.md", b"\xff"),
+            ("vault/data.csv", b"a,b"),
+        ]);
+        let res = app
+            .post_file(
+                "/notes/import",
+                "user_a",
+                "vault.zip",
+                "application/zip",
+                &archive,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: ImportReport = json(res).await;
+        assert_eq!(report.notes.len(), 2);
+        assert_eq!(report.photos.len(), 2);
+        let mut failed: Vec<_> = report.failures.iter().map(|f| f.path.as_str()).collect();
+        failed.sort();
+        assert_eq!(failed, ["vault/Broken.md", "vault/data.csv"]);
+
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        let home = notes.iter().find(|note| note.title == "Home").unwrap();
+        let trip = notes.iter().find(|note| note.title == "Trip").unwrap();
+        assert_eq!(home.tags, ["a"]);
+        assert_eq!(
+            home.created_at,
+            Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap()
+        );
+        assert_eq!(
+            home.content,
+            format!(
+                "See [my trip](/note/{0}), [[Missing]] and [trip](/note/{0}).\n\n![](cat.png)",
+                trip.id
+            )
+        );
+        assert_eq!(trip.content, "![dog](dog%201.png)");
+
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert_eq!(photos.len(), 2);
+        assert_eq!(
+            app.state
+                .storage
+                .get(&format!("photos/{}/dog 1.png", author))
+                .await
+                .unwrap()
+                .bytes,
+            &b"dog"[..]
+        );
+
+        // Imported notes were written elsewhere, so today gets no credit.
+        let stats: WritingStats = json(app.get("/notes/stats", "user_a").await).await;
+        assert!(stats.periods.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_files() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let res = app
+            .post_file(
+                "/notes/import",
+                "user_a",
+                "notes.txt",
+                "text/plain",
+                b"hello",
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,36 @@
+use std::future::Future;
+
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFuture},
+    task::TaskTracker,
+};
+
+/// Background work that outlives a request. Shutdown waits for every job
+/// spawned here, so long running jobs should stop once `cancelled` resolves.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    tracker: TaskTracker,
+    token: CancellationToken,
+}
+
+impl Jobs {
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(job);
+    }
+
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.token.cancelled()
+    }
+
+    pub fn close(&self) {
+        self.token.cancel();
+        self.tracker.close();
+    }
+
+    pub async fn wait(&self) {
+        self.tracker.wait().await;
+    }
+}
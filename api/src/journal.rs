@@ -0,0 +1,180 @@
+//! One journal note per user and day, made on first visit.
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use sqlx::{query, query_as, query_scalar, PgPool};
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    location,
+    note::{self, Note},
+    weather::{self, WeatherQuery},
+    AppState,
+};
+
+const TAG: &str = "journal";
+
+#[derive(Deserialize, IntoParams)]
+pub struct DailyQuery {
+    /// The day, as `YYYY-MM-DD`. Today in UTC when left out.
+    date: Option<NaiveDate>,
+    /// Start today's note with the current weather at your saved location.
+    #[serde(default)]
+    weather: bool,
+}
+
+async fn find(db: &PgPool, user_id: Uuid, date: NaiveDate) -> Result<Option<Note>, AppError> {
+    let note = query_as!(
+        Note,
+        "select note.* from note join journal on journal.note_id = note.id
+        where journal.user_id = $1 and journal.date = $2",
+        user_id,
+        date
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(note)
+}
+
+/// The current weather at the user's saved location, if they have one and it
+/// can be looked up.
+async fn weather_line(app: &AppState, user_id: Uuid) -> Result<Option<String>, AppError> {
+    let Some(location) = location::saved(&app.db, user_id).await? else {
+        return Ok(None);
+    };
+    let query = WeatherQuery {
+        lat: location.lat,
+        lon: location.lon,
+        unit: location.unit,
+    };
+    match weather::current(app, &query).await {
+        Ok(weather) => Ok(Some(weather.summary(location.unit))),
+        Err(AppError::WithStatus(_, error) | AppError::Internal(error)) => {
+            tracing::warn!("Couldn't stamp the journal with the weather: {}", error);
+            Ok(None)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/notes/daily",
+    tag = "notes",
+    params(DailyQuery),
+    responses((status = 200, description = "The journal note for the day, made if there wasn't one", body = Note)),
+    security(("clerk" = []))
+)]
+pub async fn daily(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<DailyQuery>,
+) -> JsonRes<Note> {
+    let today = Utc::now().date_naive();
+    let date = query.date.unwrap_or(today);
+    if let Some(note) = find(&app.db, user.id, date).await? {
+        return Ok(Json(note));
+    }
+
+    let mut content = String::new();
+    if query.weather && date == today {
+        if let Some(line) = weather_line(&app, user.id).await? {
+            content = format!("Weather: {}\n\n", line);
+        }
+    }
+    let title = date.format("%A, %-d %B %Y").to_string();
+    let note = note::insert(&app.db, user.id, &title, &content, &[TAG.to_owned()], 0).await?;
+    let claimed = query_scalar!(
+        "insert into journal (user_id, date, note_id) values ($1, $2, $3)
+        on conflict do nothing returning note_id",
+        user.id,
+        date,
+        note.id
+    )
+    .fetch_optional(&app.db)
+    .await?;
+    if claimed.is_none() {
+        // Another request made the day's note first.
+        query!("delete from note where id = $1", note.id)
+            .execute(&app.db)
+            .await?;
+        let note = find(&app.db, user.id, date)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("Journal note disappeared"))?;
+        return Ok(Json(note));
+    }
+    Ok(Json(note))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        location::SavedLocation,
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    #[tokio::test]
+    async fn makes_one_note_per_day() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let res = app.get("/notes/daily?date=2025-04-19", "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let note: Note = json(res).await;
+        assert_eq!(note.title, "Saturday, 19 April 2025");
+        assert_eq!(note.tags, ["journal"]);
+
+        let again: Note = json(app.get("/notes/daily?date=2025-04-19", "user_a").await).await;
+        assert_eq!(again.id, note.id);
+        let other: Note = json(app.get("/notes/daily?date=2025-04-20", "user_a").await).await;
+        assert_ne!(other.id, note.id);
+
+        app.delete(&format!("/note/{}", note.id), "user_a").await;
+        let replaced: Note = json(app.get("/notes/daily?date=2025-04-19", "user_a").await).await;
+        assert_ne!(replaced.id, note.id);
+    }
+
+    #[tokio::test]
+    async fn stamps_today_with_the_weather() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let note: Note = json(app.get("/notes/daily?weather=true", "user_a").await).await;
+        assert_eq!(note.content, "");
+        app.delete(&format!("/note/{}", note.id), "user_a").await;
+
+        let res = app.get("/location", "user_a").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = app
+            .post_json(
+                "/location",
+                "user_a",
+                json!({ "lat": 51.5, "lon": -0.12, "unit": "C" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let location: SavedLocation = json(app.get("/location", "user_a").await).await;
+        assert_eq!(location.lat, 51.5);
+
+        let note: Note = json(app.get("/notes/daily?weather=true", "user_a").await).await;
+        assert_eq!(note.content, "Weather: 20°C in Testville\n\n");
+        let past: Note = json(
+            app.get("/notes/daily?date=2020-01-01&weather=true", "user_a")
+                .await,
+        )
+        .await;
+        assert_eq!(past.content, "");
+    }
+}
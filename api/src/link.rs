@@ -0,0 +1,507 @@
+//! Public, read-only links to a note for people without an account. Links are
+//! managed by the note's owner and served outside of authentication.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Path, State},
+    http::{
+        header::{CONTENT_TYPE, REFERRER_POLICY},
+        HeaderMap, HeaderName, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+    Form, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, PgPool};
+use tokio::task::spawn_blocking;
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    photo,
+    render::{markdown_to_html, page},
+    share::{self, Access},
+    token::random_string,
+    AppState,
+};
+
+const TOKEN_LEN: usize = 32;
+const PASSWORD_HEADER: &str = "x-share-password";
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ShareLink {
+    id: Uuid,
+    note_id: Uuid,
+    /// The note is public at `/s/{token}`.
+    token: String,
+    protected: bool,
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct NewShareLink {
+    expires_at: Option<DateTime<Utc>>,
+    password: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/share-links",
+    tag = "sharing",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, body = Vec<ShareLink>)),
+    security(("clerk" = []))
+)]
+pub async fn get_all(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<ShareLink>> {
+    share::require(&app.db, id, user.id, Access::Owner).await?;
+    let links = query_as!(
+        ShareLink,
+        r#"select id, note_id, token, password_hash is not null as "protected!", expires_at, revoked_at, created_at
+        from share_link where note_id = $1 order by created_at desc"#,
+        id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(links))
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/share-links",
+    tag = "sharing",
+    params(("id" = Uuid, Path, description = "Note id")),
+    request_body = NewShareLink,
+    responses(
+        (status = 200, body = ShareLink),
+        (status = 400, description = "Expiry is in the past or the password is empty")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn create(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<NewShareLink>,
+) -> JsonRes<ShareLink> {
+    share::require(&app.db, id, user.id, Access::Owner).await?;
+    if doc
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(bad_request("Expiry must be in the future"));
+    }
+    let password_hash = match doc.password.as_deref() {
+        Some("") => return Err(bad_request("Password can't be empty")),
+        Some(password) => Some(hash_password(password.to_owned()).await?),
+        None => None,
+    };
+    let link = query_as!(
+        ShareLink,
+        r#"insert into share_link (note_id, token, password_hash, expires_at)
+        values ($1, $2, $3, $4)
+        returning id, note_id, token, password_hash is not null as "protected!", expires_at, revoked_at, created_at"#,
+        id,
+        random_string(TOKEN_LEN),
+        password_hash,
+        doc.expires_at
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(Json(link))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/note/{id}/share-links/{link_id}",
+    tag = "sharing",
+    params(
+        ("id" = Uuid, Path, description = "Note id"),
+        ("link_id" = Uuid, Path, description = "Share link id")
+    ),
+    responses((status = 200, description = "The revoked link", body = ShareLink)),
+    security(("clerk" = []))
+)]
+pub async fn revoke(
+    Path((id, link_id)): Path<(Uuid, Uuid)>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<ShareLink> {
+    share::require(&app.db, id, user.id, Access::Owner).await?;
+    let link = query_as!(
+        ShareLink,
+        r#"update share_link set revoked_at = now()
+        where id = $1 and note_id = $2 and revoked_at is null
+        returning id, note_id, token, password_hash is not null as "protected!", expires_at, revoked_at, created_at"#,
+        link_id,
+        id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(|| not_found("No such link"))?;
+    Ok(Json(link))
+}
+
+fn not_found(message: &'static str) -> AppError {
+    AppError::WithStatus(StatusCode::NOT_FOUND, anyhow::Error::msg(message))
+}
+
+fn bad_request(message: &'static str) -> AppError {
+    AppError::WithStatus(StatusCode::BAD_REQUEST, anyhow::Error::msg(message))
+}
+
+/// Argon2 is slow on purpose, so hashing runs off the async workers.
+async fn hash_password(password: String) -> Result<String, AppError> {
+    let hash = spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::Error::msg(e.to_string()))
+    })
+    .await??;
+    Ok(hash)
+}
+
+struct LinkedNote {
+    author_id: Uuid,
+    title: String,
+    content: String,
+    password_hash: Option<String>,
+}
+
+impl LinkedNote {
+    async fn unlocks(&self, password: Option<&str>) -> Result<bool, AppError> {
+        let Some(hash) = self.password_hash.clone() else {
+            return Ok(true);
+        };
+        let Some(password) = password.map(str::to_owned) else {
+            return Ok(false);
+        };
+        let unlocked = spawn_blocking(move || {
+            PasswordHash::new(&hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        })
+        .await?;
+        Ok(unlocked)
+    }
+}
+
+/// Unknown, revoked and expired links all look the same from outside.
+async fn find(db: &PgPool, token: &str) -> Result<LinkedNote, AppError> {
+    query_as!(
+        LinkedNote,
+        "select note.author_id, note.title, note.content, share_link.password_hash
+        from share_link join note on note.id = share_link.note_id
+        where share_link.token = $1
+          and share_link.revoked_at is null
+          and (share_link.expires_at is null or share_link.expires_at > now())",
+        token
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| not_found("Not found"))
+}
+
+/// Keeps shared pages out of search results and the token out of `Referer`.
+fn private_headers() -> [(HeaderName, &'static str); 2] {
+    [
+        (HeaderName::from_static("x-robots-tag"), "noindex"),
+        (REFERRER_POLICY, "no-referrer"),
+    ]
+}
+
+fn password_form(wrong: bool) -> Response {
+    let message = if wrong {
+        r#"<p role="alert">Wrong password.</p>"#
+    } else {
+        ""
+    };
+    let body = format!(
+        r#"<form method="post">
+<p>This note is password protected.</p>
+{}
+<input type="password" name="password" autofocus required>
+<button type="submit">View</button>
+</form>"#,
+        message
+    );
+    (
+        StatusCode::UNAUTHORIZED,
+        private_headers(),
+        Html(page("Password required", &body)),
+    )
+        .into_response()
+}
+
+async fn rendered(app: &AppState, note: &LinkedNote) -> Result<Response, AppError> {
+    let photos = photo::inline(app, note.author_id, &note.content).await?;
+    let body = markdown_to_html(&note.content, &photos);
+    Ok((private_headers(), Html(page(&note.title, &body))).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{token}",
+    tag = "sharing",
+    params(("token" = String, Path, description = "Share link token")),
+    responses(
+        (status = 200, description = "The note as an HTML page", content_type = "text/html", body = String),
+        (status = 401, description = "A form asking for the link's password", content_type = "text/html", body = String),
+        (status = 404, description = "Unknown, revoked or expired link")
+    )
+)]
+pub async fn view(
+    Path(token): Path<String>,
+    State(app): State<AppState>,
+) -> Result<Response, AppError> {
+    let note = find(&app.db, &token).await?;
+    if !note.unlocks(None).await? {
+        return Ok(password_form(false));
+    }
+    rendered(&app, &note).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Unlock {
+    password: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/s/{token}",
+    tag = "sharing",
+    params(("token" = String, Path, description = "Share link token")),
+    request_body(content = Unlock, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The note as an HTML page", content_type = "text/html", body = String),
+        (status = 401, description = "Wrong password", content_type = "text/html", body = String),
+        (status = 404, description = "Unknown, revoked or expired link")
+    )
+)]
+pub async fn unlock(
+    Path(token): Path<String>,
+    State(app): State<AppState>,
+    Form(form): Form<Unlock>,
+) -> Result<Response, AppError> {
+    let note = find(&app.db, &token).await?;
+    if !note.unlocks(Some(&form.password)).await? {
+        return Ok(password_form(true));
+    }
+    rendered(&app, &note).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{token}/raw",
+    tag = "sharing",
+    params(
+        ("token" = String, Path, description = "Share link token"),
+        ("x-share-password" = Option<String>, Header, description = "Password, for protected links")
+    ),
+    responses(
+        (status = 200, description = "The note's Markdown", content_type = "text/markdown", body = String),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "Unknown, revoked or expired link")
+    )
+)]
+pub async fn raw(
+    Path(token): Path<String>,
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let note = find(&app.db, &token).await?;
+    let password = headers
+        .get(PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if !note.unlocks(password).await? {
+        return Err(AppError::WithStatus(
+            StatusCode::UNAUTHORIZED,
+            anyhow::Error::msg(format!("Send the password in {}", PASSWORD_HEADER)),
+        ));
+    }
+    Ok((
+        private_headers(),
+        [(CONTENT_TYPE, "text/markdown; charset=utf-8")],
+        note.content,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+    use sqlx::query;
+
+    use super::ShareLink;
+    use crate::{
+        note::Note,
+        testing::{body, json, TestApp},
+    };
+
+    async fn note(app: &TestApp) -> Note {
+        app.create_user("user_a").await;
+        json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Trip", "content": "# Packing\n\n- socks" }),
+            )
+            .await,
+        )
+        .await
+    }
+
+    async fn link(app: &TestApp, note: &Note, doc: serde_json::Value) -> ShareLink {
+        let res = app
+            .post_json(&format!("/note/{}/share-links", note.id), "user_a", doc)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        json(res).await
+    }
+
+    async fn anonymous(
+        app: &TestApp,
+        request: axum::http::request::Builder,
+        payload: &str,
+    ) -> (StatusCode, String) {
+        let res = app
+            .send(request.body(Body::from(payload.to_owned())).unwrap())
+            .await;
+        let status = res.status();
+        (status, String::from_utf8(body(res).await.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_html_and_markdown() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let note = note(&app).await;
+        let link = link(&app, &note, json!({})).await;
+        assert!(!link.protected);
+
+        let (status, html) = anonymous(&app, Request::get(format!("/s/{}", link.token)), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains("<h1>Packing</h1>"));
+        assert!(html.contains("<title>Trip</title>"));
+
+        let res = app
+            .send(
+                Request::get(format!("/s/{}/raw", link.token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(
+            res.headers()["content-type"],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(&body(res).await[..], note.content.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn password_protected() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let note = note(&app).await;
+        let link = link(&app, &note, json!({ "password": "hunter2" })).await;
+        assert!(link.protected);
+        let uri = format!("/s/{}", link.token);
+
+        let (status, html) = anonymous(&app, Request::get(&uri), "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!html.contains("Packing"));
+
+        let form = Request::post(&uri).header("content-type", "application/x-www-form-urlencoded");
+        let (status, html) = anonymous(&app, form, "password=wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(html.contains("Wrong password"));
+
+        let form = Request::post(&uri).header("content-type", "application/x-www-form-urlencoded");
+        let (status, html) = anonymous(&app, form, "password=hunter2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains("Packing"));
+
+        let raw = Request::get(format!("{}/raw", uri)).header("x-share-password", "hunter2");
+        let (status, markdown) = anonymous(&app, raw, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(markdown, note.content);
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_links_are_gone() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let note = note(&app).await;
+        let past = Utc::now() - Duration::minutes(1);
+        let res = app
+            .post_json(
+                &format!("/note/{}/share-links", note.id),
+                "user_a",
+                json!({ "expires_at": past }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let revoked = link(&app, &note, json!({})).await;
+        let res = app
+            .delete(
+                &format!("/note/{}/share-links/{}", note.id, revoked.id),
+                "user_a",
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let (status, _) = anonymous(&app, Request::get(format!("/s/{}", revoked.token)), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let expiring = link(
+            &app,
+            &note,
+            json!({ "expires_at": Utc::now() + Duration::hours(1) }),
+        )
+        .await;
+        query!(
+            "update share_link set expires_at = now() - interval '1 minute' where id = $1",
+            expiring.id
+        )
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+        let (status, _) = anonymous(&app, Request::get(format!("/s/{}", expiring.token)), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let links: Vec<ShareLink> = json(
+            app.get(&format!("/note/{}/share-links", note.id), "user_a")
+                .await,
+        )
+        .await;
+        assert_eq!(links.len(), 2);
+    }
+}
@@ -0,0 +1,135 @@
+//! Where a user is, so features like the daily journal can look up the
+//! weather without asking every time.
+
+use std::str::FromStr;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, PgPool};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    weather::TemperatureUnit,
+    AppState,
+};
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SavedLocation {
+    pub lat: f64,
+    pub lon: f64,
+    pub unit: TemperatureUnit,
+}
+
+struct SavedLocationRow {
+    lat: f64,
+    lon: f64,
+    unit: String,
+}
+
+impl TryFrom<SavedLocationRow> for SavedLocation {
+    type Error = AppError;
+
+    fn try_from(row: SavedLocationRow) -> Result<Self, AppError> {
+        Ok(SavedLocation {
+            lat: row.lat,
+            lon: row.lon,
+            unit: TemperatureUnit::from_str(&row.unit)?,
+        })
+    }
+}
+
+fn not_found() -> AppError {
+    AppError::WithStatus(
+        StatusCode::NOT_FOUND,
+        anyhow::Error::msg("No saved location"),
+    )
+}
+
+pub async fn saved(db: &PgPool, user_id: Uuid) -> Result<Option<SavedLocation>, AppError> {
+    query_as!(
+        SavedLocationRow,
+        "select lat, lon, unit from user_location where user_id = $1",
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .map(SavedLocation::try_from)
+    .transpose()
+}
+
+#[utoipa::path(
+    get,
+    path = "/location",
+    tag = "location",
+    responses(
+        (status = 200, body = SavedLocation),
+        (status = 404, description = "No location saved yet")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn get(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<SavedLocation> {
+    let location = saved(&app.db, user.id).await?.ok_or_else(not_found)?;
+    Ok(Json(location))
+}
+
+#[utoipa::path(
+    post,
+    path = "/location",
+    tag = "location",
+    request_body = SavedLocation,
+    responses((status = 200, body = SavedLocation)),
+    security(("clerk" = []))
+)]
+pub async fn save(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<SavedLocation>,
+) -> JsonRes<SavedLocation> {
+    let location = query_as!(
+        SavedLocationRow,
+        "insert into user_location (user_id, lat, lon, unit) values ($1, $2, $3, $4)
+        on conflict (user_id) do update set lat = $2, lon = $3, unit = $4
+        returning lat, lon, unit",
+        user.id,
+        doc.lat,
+        doc.lon,
+        doc.unit.to_string()
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(Json(location.try_into()?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/location",
+    tag = "location",
+    responses(
+        (status = 200, description = "The forgotten location", body = SavedLocation),
+        (status = 404, description = "No location saved")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn delete(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<SavedLocation> {
+    let location = query_as!(
+        SavedLocationRow,
+        "delete from user_location where user_id = $1 returning lat, lon, unit",
+        user.id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    Ok(Json(location.try_into()?))
+}
@@ -1,34 +1,70 @@
-macro_rules! env_var {
-    ($l: expr) => {{
-        let val = std::env::var(String::from($l)).expect($l);
-        val
-    }};
-}
-
+mod attachment;
+mod auth;
 mod clerk;
+mod cli;
+mod config;
 mod error;
+mod export;
+mod health;
+mod import;
+mod jobs;
+mod journal;
+mod link;
+mod location;
+mod metadata;
+mod migrate;
 mod note;
+mod openapi;
+mod outline;
 mod photo;
+mod photo_ref;
+mod rate_limit;
+mod render;
+mod share;
+mod stats;
+mod storage;
+mod telemetry;
+mod template;
+#[cfg(test)]
+mod testing;
+mod token;
 mod weather;
+mod wikilink;
 
 use anyhow::Result;
+use auth::{Auth, UserCache};
 use aws_config::{BehaviorVersion, Region};
 use axum::{
-    extract::{MatchedPath, Request},
+    extract::{DefaultBodyLimit, MatchedPath, Request},
     http::{
-        header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
-        HeaderValue, Method,
+        header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+        HeaderName, Method,
     },
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
-use clerk_rs::validators::{axum::ClerkLayer, jwks::MemoryCacheJwksProvider};
+use clap::Parser;
+use clerk_rs::validators::{authorizer::ClerkAuthorizer, jwks::MemoryCacheJwksProvider};
 use clerk_rs::{clerk::Clerk, ClerkConfiguration};
+use cli::{Cli, Command, MigrateCommand};
+use config::Config;
 use dotenv::dotenv;
+use jobs::Jobs;
+use metrics_exporter_prometheus::PrometheusHandle;
+use rate_limit::RateLimiter;
 use reqwest::Client;
 use serde::Deserialize;
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::path::PathBuf;
+use std::{
+    future::IntoFuture,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use storage::Storage;
+use tokio_util::sync::CancellationToken;
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
     services::ServeDir,
@@ -36,12 +72,19 @@ use tower_http::{
 };
 use tracing::info_span;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use ts_rs::TS;
 
 #[derive(Clone)]
 struct AppState {
+    config: Arc<Config>,
     db: PgPool,
     reqwest: Client,
-    s3: aws_sdk_s3::Client,
+    storage: Storage,
+    auth: Auth,
+    users: Arc<UserCache>,
+    jobs: Jobs,
+    metrics: PrometheusHandle,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 #[derive(Deserialize)]
@@ -65,58 +108,168 @@ async fn main() -> Result<()> {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve { migrate: false }) {
+        Command::Serve { migrate } => serve(migrate).await,
+        Command::Migrate { command } => {
+            let db = PgPoolOptions::new()
+                .max_connections(1)
+                .connect(&Config::database_url()?)
+                .await?;
+            match command {
+                MigrateCommand::Up => migrate::up(&db).await,
+                MigrateCommand::Down { target } => migrate::down(&db, target).await,
+                MigrateCommand::Status => migrate::status(&db).await,
+            }
+        }
+        Command::RecountNotes => {
+            let db = PgPoolOptions::new()
+                .max_connections(1)
+                .connect(&Config::database_url()?)
+                .await?;
+            let updated = stats::recount_all(&db).await?;
+            tracing::info!("Updated the stats of {} notes", updated);
+            Ok(())
+        }
+        Command::ExportBindings { out_dir } => export_bindings(&out_dir),
+    }
+}
+
+async fn serve(migrate: bool) -> Result<()> {
+    let config = Arc::new(Config::load()?);
+    let metrics = telemetry::install()?;
     let db = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&env_var!("DATABASE_URL"))
+        .max_connections(config.db_max_connections)
+        .connect(&config.database_url)
         .await?;
-    let config = ClerkConfiguration::new(None, None, Some(env_var!("CLERK_SECRET_KEY")), None);
-    let clerk = Clerk::new(config);
+    if migrate || config.auto_migrate {
+        migrate::up(&db).await?;
+    }
+    let clerk_config =
+        ClerkConfiguration::new(None, None, Some(config.clerk_secret_key.clone()), None);
+    let clerk = Clerk::new(clerk_config);
     let reqwest = Client::new();
-    let config = aws_config::load_defaults(BehaviorVersion::latest())
+    let s3_config = aws_config::load_defaults(BehaviorVersion::latest())
         .await
         .into_builder()
-        .endpoint_url(env_var!("S3_URL"))
+        .endpoint_url(&config.s3_url)
         .region(Region::new("auto"))
         .credentials_provider(aws_sdk_s3::config::SharedCredentialsProvider::new(
             aws_sdk_s3::config::Credentials::new(
-                env_var!("R2_ACCESS_KEY_ID"),
-                env_var!("R2_ACCESS_KEY_SECRET"),
+                &config.r2_access_key_id,
+                &config.r2_access_key_secret,
                 None,
                 None,
                 "r2",
             ),
         ))
         .build();
-    let s3 = aws_sdk_s3::Client::new(&config);
-    let allow_origin = env_var!("ALLOW_ORIGIN")
-        .parse::<String>()?
-        .split(",")
-        .map(|s| s.trim().parse::<HeaderValue>().unwrap())
-        .collect::<Vec<_>>();
-    let allow_origin = AllowOrigin::list(allow_origin.into_iter());
-    let app = Router::new()
-        .route("/notes", get(note::get_all).post(note::create))
-        .route(
-            "/note/:id",
-            get(note::get).post(note::update).delete(note::delete),
-        )
-        .route("/weather", get(weather::get))
-        .route("/photos", get(photo::get_all).post(photo::upload))
-        .route("/photos/:name", get(photo::view))
-        .layer(ClerkLayer::new(
-            MemoryCacheJwksProvider::new(clerk.clone()),
-            None,
+    let storage = Storage::S3 {
+        client: aws_sdk_s3::Client::new(&s3_config),
+        bucket: config.s3_bucket.clone(),
+    };
+    let state = AppState {
+        config: config.clone(),
+        db,
+        reqwest,
+        storage,
+        auth: Auth::Clerk(ClerkAuthorizer::new(
+            MemoryCacheJwksProvider::new(clerk),
             true,
+        )),
+        users: Arc::default(),
+        jobs: Jobs::default(),
+        metrics,
+        rate_limiter: Arc::default(),
+    };
+    rate_limit::spawn_pruning(&state);
+    photo_ref::spawn_collection(&state);
+    let app = build_app(state.clone());
+    let listener = tokio::net::TcpListener::bind(&config.bind_address).await?;
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .into_future(),
+    );
+    shutdown_signal().await;
+    tracing::info!("Shutting down, waiting for in-flight requests and jobs");
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(config.shutdown_timeout_secs);
+    shutdown.cancel();
+    state.jobs.close();
+    let drain = async {
+        let served = server.await;
+        state.jobs.wait().await;
+        served
+    };
+    match tokio::time::timeout_at(deadline, drain).await {
+        Ok(served) => served??,
+        Err(_) => tracing::warn!("Shutdown deadline exceeded, dropping remaining work"),
+    }
+    // Closing waits for connections still held by dropped work, so it shares
+    // the deadline rather than extending it.
+    if tokio::time::timeout_at(deadline, state.db.close())
+        .await
+        .is_err()
+    {
+        tracing::warn!("Shutdown deadline exceeded, leaving database connections open");
+    }
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn build_app(state: AppState) -> Router {
+    protected_routes(&state)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
         ))
         .nest_service("/assets", ServeDir::new("/assets"))
-        .route("/clerk-webhook", post(clerk::post_webhook))
+        .merge(public_routes(&state))
+        .merge(openapi::docs())
         .route("/", get(root))
         .layer(
             CorsLayer::new()
-                .allow_origin(allow_origin)
+                .allow_origin(AllowOrigin::list(state.config.allow_origin.clone()))
                 .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE])
-                .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS]),
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PATCH,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                ])
+                .expose_headers([
+                    HeaderName::from_static("ratelimit-limit"),
+                    HeaderName::from_static("ratelimit-remaining"),
+                    HeaderName::from_static("ratelimit-reset"),
+                    RETRY_AFTER,
+                ]),
         )
+        .layer(middleware::from_fn(telemetry::track))
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
                 let matched_path = request
@@ -132,9 +285,136 @@ async fn main() -> Result<()> {
                 )
             }),
         )
-        .with_state(AppState { db, reqwest, s3 });
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
-    axum::serve(listener, app).await?;
+        .with_state(state)
+}
+
+/// Routes that require a Clerk session or an API token. Anything added here
+/// should also be listed in `openapi::ApiDoc`.
+fn protected_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/notes", get(note::get_all).post(note::create))
+        .route("/notes/shared", get(share::shared_with_me))
+        .route("/notes/daily", get(journal::daily))
+        .route("/notes/stats", get(stats::get))
+        .route("/notes/export", get(export::notes))
+        .route("/notes/export.csv", get(metadata::notes))
+        .route("/notes/from-template/:id", post(template::instantiate))
+        .route(
+            "/notes/import",
+            post(import::notes).layer(DefaultBodyLimit::max(import::MAX_ARCHIVE_BYTES)),
+        )
+        .route(
+            "/note/:id",
+            get(note::get)
+                .post(note::update)
+                .patch(note::patch)
+                .delete(note::delete),
+        )
+        .route("/note/:id/render", get(note::render))
+        .route("/note/:id/outline", get(outline::get))
+        .route("/note/:id/pin", post(note::pin))
+        .route("/note/:id/favorite", post(note::favorite))
+        .route("/note/:id/archive", post(note::archive))
+        .route(
+            "/note/:id/attachments",
+            get(attachment::get_all)
+                .post(attachment::upload)
+                .layer(DefaultBodyLimit::max(attachment::MAX_ATTACHMENT_BYTES)),
+        )
+        .route(
+            "/note/:id/attachments/:attachment_id",
+            get(attachment::download).delete(attachment::delete),
+        )
+        .route("/note/:id/backlinks", get(wikilink::backlinks))
+        .route("/note/:id/links", get(wikilink::outgoing))
+        .route("/note/:id/shares", get(share::get_all).post(share::create))
+        .route("/note/:id/shares/:user_id", delete(share::delete))
+        .route(
+            "/note/:id/share-links",
+            get(link::get_all).post(link::create),
+        )
+        .route("/note/:id/share-links/:link_id", delete(link::revoke))
+        .route("/templates", get(template::get_all).post(template::create))
+        .route(
+            "/template/:id",
+            post(template::update).delete(template::delete),
+        )
+        .route("/weather", get(weather::get))
+        .route(
+            "/location",
+            get(location::get)
+                .post(location::save)
+                .delete(location::delete),
+        )
+        .route("/photos", get(photo::get_all).post(photo::upload))
+        .route("/photos/export.csv", get(metadata::photos))
+        .route("/photos/captions", post(metadata::import_captions))
+        .route("/photos/gc", post(photo_ref::gc))
+        .route("/photos/:name", get(photo::view))
+        .route("/photos/:name/notes", get(photo_ref::notes))
+        .route("/tokens", get(token::get_all).post(token::create))
+        .route("/token/:id", delete(token::revoke))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ))
+}
+
+fn public_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/clerk-webhook",
+            post(clerk::post_webhook).layer(middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit::limit,
+            )),
+        )
+        .nest(
+            "/s/:token",
+            Router::new()
+                .route("/", get(link::view).post(link::unlock))
+                .route("/raw", get(link::raw))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit::limit,
+                )),
+        )
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .route("/metrics", get(telemetry::get))
+}
+
+fn export_bindings(out_dir: &Path) -> Result<()> {
+    attachment::Attachment::export_all_to(out_dir)?;
+    location::SavedLocation::export_all_to(out_dir)?;
+    metadata::CaptionImport::export_all_to(out_dir)?;
+    note::Note::export_all_to(out_dir)?;
+    note::NewNote::export_all_to(out_dir)?;
+    note::UpdateNote::export_all_to(out_dir)?;
+    note::TextEdit::export_all_to(out_dir)?;
+    note::NotePatch::export_all_to(out_dir)?;
+    note::NoteVersion::export_all_to(out_dir)?;
+    import::ImportReport::export_all_to(out_dir)?;
+    outline::OutlineHeading::export_all_to(out_dir)?;
+    photo::Photo::export_all_to(out_dir)?;
+    photo_ref::PhotoCollection::export_all_to(out_dir)?;
+    link::ShareLink::export_all_to(out_dir)?;
+    link::NewShareLink::export_all_to(out_dir)?;
+    share::NoteShare::export_all_to(out_dir)?;
+    share::NewNoteShare::export_all_to(out_dir)?;
+    share::SharedNote::export_all_to(out_dir)?;
+    stats::WritingPeriod::export_all_to(out_dir)?;
+    stats::WritingStats::export_all_to(out_dir)?;
+    template::NoteTemplate::export_all_to(out_dir)?;
+    template::NewNoteTemplate::export_all_to(out_dir)?;
+    template::UpdateNoteTemplate::export_all_to(out_dir)?;
+    token::ApiToken::export_all_to(out_dir)?;
+    token::NewApiToken::export_all_to(out_dir)?;
+    token::CreatedApiToken::export_all_to(out_dir)?;
+    weather::Weather::export_all_to(out_dir)?;
+    weather::CurrentWeather::export_all_to(out_dir)?;
+    wikilink::NoteLink::export_all_to(out_dir)?;
     Ok(())
 }
 
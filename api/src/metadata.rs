@@ -0,0 +1,338 @@
+//! Note and photo metadata as CSV, for spreadsheets. Photo captions can be
+//! edited there and uploaded again.
+
+use std::borrow::Cow;
+
+use axum::{
+    extract::{Multipart, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, query_scalar};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    note::Note,
+    photo::Photo,
+    AppState,
+};
+
+/// Separates tags within their column.
+const TAG_SEPARATOR: &str = ";";
+
+/// Spreadsheets run cells starting with these as formulas.
+const FORMULA_STARTS: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+/// `text` as a cell a spreadsheet shows as it is, with a `'` in front of
+/// anything that would otherwise be a formula.
+fn cell(text: &str) -> Cow<'_, str> {
+    if text.starts_with(FORMULA_STARTS) {
+        Cow::Owned(format!("'{}", text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Undoes `cell`, for sheets uploaded again.
+fn uncell(text: String) -> String {
+    match text.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_STARTS) => rest.to_owned(),
+        _ => text,
+    }
+}
+
+#[derive(Serialize)]
+struct NoteRow<'a> {
+    id: Uuid,
+    title: Cow<'a, str>,
+    tags: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    word_count: i32,
+}
+
+#[derive(Serialize)]
+struct PhotoRow<'a> {
+    name: Cow<'a, str>,
+    caption: Cow<'a, str>,
+    size_b: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// A row of an uploaded caption sheet. Other columns, such as the rest of an
+/// export, are ignored.
+#[derive(Deserialize)]
+struct CaptionRow {
+    name: String,
+    caption: String,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CaptionImport {
+    /// Photos whose caption was set.
+    updated: Vec<String>,
+    /// Names in the sheet that aren't one of your photos.
+    not_found: Vec<String>,
+}
+
+/// Multipart body for `import_captions`. Only used to document the endpoint.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct CaptionUpload {
+    /// A CSV file with `name` and `caption` columns.
+    #[schema(format = Binary, content_media_type = "text/csv")]
+    file: String,
+}
+
+fn write_csv<T: Serialize>(rows: impl IntoIterator<Item = T>) -> Result<Vec<u8>, AppError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    Ok(writer.into_inner().map_err(|error| error.into_error())?)
+}
+
+fn attachment(file_name: &str, csv: Vec<u8>) -> impl IntoResponse {
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8".to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        csv,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/notes/export.csv",
+    tag = "notes",
+    responses((
+        status = 200,
+        description = "One row per note with its id, title, tags, dates and word count",
+        content_type = "text/csv"
+    )),
+    security(("clerk" = []))
+)]
+pub async fn notes(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<impl IntoResponse, AppError> {
+    let notes = query_as!(
+        Note,
+        "select * from note where author_id = $1 order by created_at",
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let csv = write_csv(notes.iter().map(|note| NoteRow {
+        id: note.id,
+        title: cell(&note.title),
+        tags: cell(&note.tags.join(TAG_SEPARATOR)).into_owned(),
+        created_at: note.created_at,
+        updated_at: note.updated_at,
+        word_count: note.word_count,
+    }))?;
+    Ok(attachment("notes.csv", csv))
+}
+
+#[utoipa::path(
+    get,
+    path = "/photos/export.csv",
+    tag = "photos",
+    responses((
+        status = 200,
+        description = "One row per photo with its name, caption, size and dates",
+        content_type = "text/csv"
+    )),
+    security(("clerk" = []))
+)]
+pub async fn photos(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<impl IntoResponse, AppError> {
+    let photos = query_as!(
+        Photo,
+        "select * from photo where author_id = $1 order by created_at",
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let csv = write_csv(photos.iter().map(|photo| PhotoRow {
+        name: cell(&photo.name),
+        caption: cell(&photo.caption),
+        size_b: photo.size_b,
+        created_at: photo.created_at,
+        updated_at: photo.updated_at,
+    }))?;
+    Ok(attachment("photos.csv", csv))
+}
+
+#[utoipa::path(
+    post,
+    path = "/photos/captions",
+    tag = "photos",
+    request_body(content = CaptionUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = CaptionImport),
+        (status = 400, description = "No file, or a malformed CSV")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn import_captions(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    mut multipart: Multipart,
+) -> JsonRes<CaptionImport> {
+    let bad_request = |message: String| {
+        AppError::WithStatus(StatusCode::BAD_REQUEST, anyhow::Error::msg(message))
+    };
+    let Some(field) = multipart.next_field().await? else {
+        return Err(bad_request("Expected a CSV file".to_owned()));
+    };
+    let bytes = field.bytes().await?;
+
+    let mut names = Vec::new();
+    let mut captions = Vec::new();
+    for row in csv::Reader::from_reader(&bytes[..]).deserialize() {
+        let row: CaptionRow =
+            row.map_err(|error| bad_request(format!("Invalid CSV: {}", error)))?;
+        names.push(uncell(row.name));
+        captions.push(uncell(row.caption));
+    }
+
+    let updated = query_scalar!(
+        "update photo set caption = data.caption
+        from unnest($1::text[], $2::text[]) as data(name, caption)
+        where photo.name = data.name and photo.author_id = $3
+        returning photo.name",
+        &names,
+        &captions,
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let not_found = names
+        .into_iter()
+        .filter(|name| !updated.contains(name))
+        .collect();
+    Ok(Json(CaptionImport { updated, not_found }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::CONTENT_TYPE, StatusCode};
+    use serde_json::json;
+
+    use super::{cell, uncell, CaptionImport};
+    use crate::{
+        photo::Photo,
+        testing::{body, json, TestApp},
+    };
+
+    async fn csv(app: &TestApp, uri: &str) -> String {
+        let res = app.get(uri, "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; charset=utf-8");
+        String::from_utf8(body(res).await.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn escapes_formulas() {
+        assert_eq!(cell("=HYPERLINK(\"x\")"), "'=HYPERLINK(\"x\")");
+        assert_eq!(cell("-1"), "'-1");
+        assert_eq!(cell("it's fine"), "it's fine");
+        for text in ["@me", "+1 555", "'quoted", "plain"] {
+            assert_eq!(uncell(cell(text).into_owned()), text);
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_notes() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.post_json(
+            "/notes",
+            "user_a",
+            json!({ "title": "Plans, mostly", "content": "# Plan\n\nGo *outside* today", "tags": ["a", "b"] }),
+        )
+        .await;
+
+        let csv = csv(&app, "/notes/export.csv").await;
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,title,tags,created_at,updated_at,word_count")
+        );
+        let row = lines.next().unwrap();
+        assert!(row.contains(",\"Plans, mostly\",a;b,"));
+        assert!(row.ends_with(",4"));
+        assert!(lines.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn updates_captions() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        app.post_file("/photos", "user_a", "cat.png", "image/png", b"cat")
+            .await;
+        app.post_file("/photos", "user_b", "dog.png", "image/png", b"dog")
+            .await;
+
+        let exported = csv(&app, "/photos/export.csv").await;
+        assert!(exported.starts_with("name,caption,size_b,created_at,updated_at\ncat.png,,3,"));
+
+        let sheet = "name,caption,size_b\ncat.png,\"'=A cat, asleep\",3\ndog.png,Not mine,3\n";
+        let res = app
+            .post_file(
+                "/photos/captions",
+                "user_a",
+                "captions.csv",
+                "text/csv",
+                sheet.as_bytes(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: CaptionImport = json(res).await;
+        assert_eq!(report.updated, ["cat.png"]);
+        assert_eq!(report.not_found, ["dog.png"]);
+
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert_eq!(photos[0].caption, "=A cat, asleep");
+        let exported = csv(&app, "/photos/export.csv").await;
+        assert!(exported.contains("cat.png,\"'=A cat, asleep\",3,"));
+        let photos: Vec<Photo> = json(app.get("/photos", "user_b").await).await;
+        assert_eq!(photos[0].caption, "");
+
+        let res = app
+            .post_file(
+                "/photos/captions",
+                "user_a",
+                "captions.csv",
+                "text/csv",
+                b"title\nx\n",
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    PgPool,
+};
+
+use crate::stats;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Adds the stats kept with each note, which start out as zeroes.
+const NOTE_STATS: i64 = 20250524090000;
+
+pub async fn up(db: &PgPool) -> Result<()> {
+    let applied = applied_versions(db).await?;
+    MIGRATOR.run(db).await?;
+    if !applied.contains(&NOTE_STATS) {
+        let updated = stats::recount_all(db).await?;
+        tracing::info!("Worked out the stats of {} notes", updated);
+    }
+    tracing::info!("Migrations are up to date");
+    Ok(())
+}
+
+/// Reverts every applied migration newer than `target`. Without a target
+/// only the latest migration is reverted.
+pub async fn down(db: &PgPool, target: Option<i64>) -> Result<()> {
+    let applied = applied_versions(db).await?;
+    let target = match target {
+        Some(target) => target,
+        None => match applied.as_slice() {
+            [] => {
+                tracing::info!("No migrations to revert");
+                return Ok(());
+            }
+            [.., previous, _] => *previous,
+            [_] => 0,
+        },
+    };
+    MIGRATOR.undo(db, target).await?;
+    tracing::info!("Reverted migrations newer than {}", target);
+    Ok(())
+}
+
+pub async fn status(db: &PgPool) -> Result<()> {
+    let applied = applied_versions(db).await?;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+    {
+        let state = if applied.contains(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!(
+            "{}  {:<8} {}",
+            migration.version, state, migration.description
+        );
+    }
+    Ok(())
+}
+
+pub async fn applied_versions(db: &PgPool) -> Result<Vec<i64>> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let mut versions = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect::<Vec<_>>();
+    versions.sort();
+    Ok(versions)
+}
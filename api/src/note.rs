@@ -1,63 +1,204 @@
-use crate::{clerk::get_user, error::JsonRes, AppState};
+use crate::{
+    attachment,
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    photo, photo_ref,
+    render::{markdown_to_html, replace_spans},
+    share::{self, Access},
+    stats::{self, NoteStats},
+    wikilink, AppState,
+};
 use axum::{
-    extract::{Path, State},
-    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
 };
 use chrono::{DateTime, Utc};
-use clerk_rs::validators::authorizer::ClerkJwt;
 use serde::{self, Deserialize, Serialize};
-use sqlx::{query, query_as};
+use sqlx::{query, query_as, PgPool};
 use ts_rs::TS;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 #[derive(TS)]
 #[ts(export)]
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct Note {
-    id: Uuid,
-    author_id: Uuid,
-    title: String,
-    content: String,
-    created_at: DateTime<Utc>,
-    updated_at: DateTime<Utc>,
+    pub id: Uuid,
+    pub author_id: Uuid,
+    pub title: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    /// Listed before other notes.
+    pub pinned: bool,
+    pub favorite: bool,
+    /// Left out of the note list unless asked for.
+    pub archived: bool,
+    pub word_count: i32,
+    pub char_count: i32,
+    pub reading_minutes: i32,
+    /// The headings as Markdown without inline syntax, e.g. `## Goals`.
+    pub outline: Vec<String>,
+    /// Goes up by one whenever the content changes.
+    pub version: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(TS)]
 #[ts(export)]
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct UpdateNote {
     content: Option<String>,
     title: Option<String>,
+    tags: Option<Vec<String>>,
 }
 
 #[derive(TS)]
 #[ts(export)]
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct NewNote {
     title: String,
     content: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Replaces the bytes from `start` up to `end` with `text`. Both are byte
+/// offsets into the content the patch was made against.
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct TextEdit {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct NotePatch {
+    /// The version of the content the edits were made against.
+    version: i32,
+    /// Edits that don't overlap, applied together. Insertions at the same
+    /// offset go in the order given.
+    edits: Vec<TextEdit>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NoteVersion {
+    pub id: Uuid,
+    pub version: i32,
+    pub updated_at: DateTime<Utc>,
 }
 
-pub async fn get(Path(id): Path<Uuid>, State(app): State<AppState>) -> JsonRes<Note> {
+#[derive(Deserialize, IntoParams)]
+pub struct NotesQuery {
+    /// Include archived notes.
+    #[serde(default)]
+    archived: bool,
+    /// Only favorite notes.
+    #[serde(default)]
+    favorite: bool,
+}
+
+/// A state of a note that can be switched on and off.
+#[derive(Clone, Copy)]
+enum Flag {
+    Pinned,
+    Favorite,
+    Archived,
+}
+
+/// Switches `flag` on the note, leaving its other states as they are.
+async fn toggle(app: &AppState, id: Uuid, user_id: Uuid, flag: Flag) -> JsonRes<Note> {
+    share::require(&app.db, id, user_id, Access::Owner).await?;
+    // Each column is flipped when its parameter is true.
+    let note = query_as!(
+        Note,
+        "update note set pinned = pinned <> $2, favorite = favorite <> $3, archived = archived <> $4
+        where id = $1 returning *",
+        id,
+        matches!(flag, Flag::Pinned),
+        matches!(flag, Flag::Favorite),
+        matches!(flag, Flag::Archived)
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(Json(note))
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, body = Note)),
+    security(("clerk" = []))
+)]
+pub async fn get(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Note> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
     let note = query_as!(Note, "select * from note where id = $1", id)
         .fetch_one(&app.db)
         .await?;
     Ok(Json(note))
 }
 
+#[utoipa::path(
+    get,
+    path = "/note/{id}/render",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((
+        status = 200,
+        description = "The note as sanitized HTML, with embedded photos inlined",
+        content_type = "text/html",
+        body = String
+    )),
+    security(("clerk" = []))
+)]
+pub async fn render(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Html<String>, AppError> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let note = query_as!(Note, "select * from note where id = $1", id)
+        .fetch_one(&app.db)
+        .await?;
+    let photos = photo::inline(&app, note.author_id, &note.content).await?;
+    Ok(Html(markdown_to_html(&note.content, &photos)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    request_body = UpdateNote,
+    responses((status = 200, body = Note)),
+    security(("clerk" = []))
+)]
 pub async fn update(
     Path(id): Path<Uuid>,
     State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
     Json(doc): Json<UpdateNote>,
 ) -> JsonRes<Note> {
-    if doc.content.is_some() {
-        query!(
-            "update note set content = $1 where id = $2",
-            doc.content,
-            id
-        )
-        .execute(&app.db)
+    share::require(&app.db, id, user.id, Access::Editor).await?;
+    let before = query_as!(Note, "select * from note where id = $1", id)
+        .fetch_one(&app.db)
         .await?;
+    if let Some(content) = &doc.content {
+        set_content(&app.db, id, content, None).await?;
     }
     if doc.title.is_some() {
         query_as!(
@@ -69,37 +210,206 @@ pub async fn update(
         .execute(&app.db)
         .await?;
     }
+    if let Some(tags) = doc.tags {
+        query!("update note set tags = $1 where id = $2", &tags, id)
+            .execute(&app.db)
+            .await?;
+    }
     let note = query_as!(Note, "select * from note where id = $1", id)
         .fetch_one(&app.db)
         .await?;
+    saved(&app, user.id, &before, &note).await?;
     Ok(Json(note))
 }
 
+/// Keeps what is recorded about a note in step with a change `user_id` made.
+async fn saved(app: &AppState, user_id: Uuid, before: &Note, note: &Note) -> Result<(), AppError> {
+    if note.content != before.content {
+        stats::record(&app.db, user_id, before.word_count, note.word_count).await?;
+        wikilink::sync(&app.db, note.id, note.author_id, &note.content).await?;
+        photo_ref::sync(&app.db, note.id, note.author_id, &note.content).await?;
+    }
+    if note.title != before.title {
+        wikilink::retitle(&app.db, note.id, &before.title, &note.title).await?;
+    }
+    Ok(())
+}
+
+/// `content` with `edits` made to it, or why they can't be.
+fn apply(content: &str, mut edits: Vec<TextEdit>) -> Result<String, String> {
+    edits.sort_by_key(|edit| edit.start);
+    let mut last_end = 0;
+    for edit in &edits {
+        if edit.start > edit.end || edit.end > content.len() {
+            return Err(format!("Edit {}..{} is out of range", edit.start, edit.end));
+        }
+        if !content.is_char_boundary(edit.start) || !content.is_char_boundary(edit.end) {
+            return Err(format!(
+                "Edit {}..{} splits a character",
+                edit.start, edit.end
+            ));
+        }
+        if edit.start < last_end {
+            return Err(format!(
+                "Edit {}..{} overlaps another",
+                edit.start, edit.end
+            ));
+        }
+        last_end = edit.end;
+    }
+    let replacements = edits
+        .into_iter()
+        .map(|edit| (edit.start..edit.end, edit.text))
+        .collect();
+    Ok(replace_spans(content, replacements))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/note/{id}",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    request_body = NotePatch,
+    responses(
+        (status = 200, description = "The new version of the content", body = NoteVersion),
+        (status = 400, description = "An edit is out of range or overlaps another"),
+        (status = 409, description = "The content has changed since the given version")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn patch(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<NotePatch>,
+) -> JsonRes<NoteVersion> {
+    share::require(&app.db, id, user.id, Access::Editor).await?;
+    let conflict = || {
+        AppError::WithStatus(
+            StatusCode::CONFLICT,
+            anyhow::Error::msg("The note has changed since this version"),
+        )
+    };
+    let before = query_as!(Note, "select * from note where id = $1", id)
+        .fetch_one(&app.db)
+        .await?;
+    if before.version != doc.version {
+        return Err(conflict());
+    }
+    let content = apply(&before.content, doc.edits).map_err(|message| {
+        AppError::WithStatus(StatusCode::BAD_REQUEST, anyhow::Error::msg(message))
+    })?;
+    // Another save may have got in since the note was read.
+    if !set_content(&app.db, id, &content, Some(doc.version)).await? {
+        return Err(conflict());
+    }
+    let note = query_as!(Note, "select * from note where id = $1", id)
+        .fetch_one(&app.db)
+        .await?;
+    saved(&app, user.id, &before, &note).await?;
+    Ok(Json(NoteVersion {
+        id: note.id,
+        version: note.version,
+        updated_at: note.updated_at,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notes",
+    tag = "notes",
+    request_body = NewNote,
+    responses((status = 200, body = Note)),
+    security(("clerk" = []))
+)]
 #[axum::debug_handler]
 pub async fn create(
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
     Json(doc): Json<NewNote>,
 ) -> JsonRes<Note> {
-    let user = get_user(&app.db, &jwt.sub).await?;
+    let words = NoteStats::of(&doc.content).word_count;
+    let note = insert(&app.db, user.id, &doc.title, &doc.content, &doc.tags, words).await?;
+    Ok(Json(note))
+}
+
+/// Replaces a note's content, along with the stats kept from it, as long as
+/// it is still at `expected_version` when one is given. Returns whether it
+/// was replaced.
+pub async fn set_content(
+    db: &PgPool,
+    id: Uuid,
+    content: &str,
+    expected_version: Option<i32>,
+) -> Result<bool, AppError> {
+    let stats = NoteStats::of(content);
+    let updated = query!(
+        "update note set content = $2, word_count = $3, char_count = $4, reading_minutes = $5,
+            outline = $6, version = case when content <> $2 then version + 1 else version end
+        where id = $1 and ($7::integer is null or version = $7)",
+        id,
+        content,
+        stats.word_count,
+        stats.char_count,
+        stats.reading_minutes,
+        &stats.outline,
+        expected_version
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(updated > 0)
+}
+
+/// Saves a new note, records the notes and photos it refers to and credits
+/// `credited` words to the author's day. Text the author didn't write, such
+/// as a template's, shouldn't be credited.
+pub async fn insert(
+    db: &PgPool,
+    author_id: Uuid,
+    title: &str,
+    content: &str,
+    tags: &[String],
+    credited: i32,
+) -> Result<Note, AppError> {
+    let stats = NoteStats::of(content);
     let note = query_as!(
         Note,
-        "insert into note (title, content, author_id) values ($1, $2, $3) returning *",
-        doc.title,
-        doc.content,
-        user.id
+        "insert into note (title, content, tags, author_id, word_count, char_count,
+            reading_minutes, outline)
+        values ($1, $2, $3, $4, $5, $6, $7, $8) returning *",
+        title,
+        content,
+        tags,
+        author_id,
+        stats.word_count,
+        stats.char_count,
+        stats.reading_minutes,
+        &stats.outline
     )
-    .fetch_one(&app.db)
+    .fetch_one(db)
     .await?;
-    Ok(Json(note))
+    wikilink::sync(db, note.id, note.author_id, &note.content).await?;
+    photo_ref::sync(db, note.id, note.author_id, &note.content).await?;
+    stats::record(db, author_id, 0, credited).await?;
+    Ok(note)
 }
 
+#[utoipa::path(
+    delete,
+    path = "/note/{id}",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "The deleted note", body = Note)),
+    security(("clerk" = []))
+)]
 pub async fn delete(
     Path(id): Path<Uuid>,
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
 ) -> JsonRes<Note> {
-    let user = get_user(&app.db, &jwt.sub).await?;
+    share::require(&app.db, id, user.id, Access::Owner).await?;
+    let attachments = attachment::for_note(&app.db, id).await?;
     let note = query_as!(
         Note,
         "delete from note where id = $1 and author_id = $2  returning *",
@@ -108,16 +418,338 @@ pub async fn delete(
     )
     .fetch_one(&app.db)
     .await?;
+    attachment::remove_files(&app.storage, &attachments).await;
     Ok(Json(note))
 }
 
+#[utoipa::path(
+    get,
+    path = "/notes",
+    tag = "notes",
+    params(NotesQuery),
+    responses((status = 200, description = "Your notes, pinned ones first", body = Vec<Note>)),
+    security(("clerk" = []))
+)]
 pub async fn get_all(
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<NotesQuery>,
 ) -> JsonRes<Vec<Note>> {
-    let user = get_user(&app.db, &jwt.sub).await?;
-    let notes = query_as!(Note, "select * from note where author_id = $1", user.id)
-        .fetch_all(&app.db)
-        .await?;
+    let notes = query_as!(
+        Note,
+        "select * from note
+        where author_id = $1 and (not archived or $2) and (favorite or not $3)
+        order by pinned desc, updated_at desc",
+        user.id,
+        query.archived,
+        query.favorite
+    )
+    .fetch_all(&app.db)
+    .await?;
     Ok(Json(notes))
 }
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/pin",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "The note, pinned or unpinned", body = Note)),
+    security(("clerk" = []))
+)]
+pub async fn pin(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Note> {
+    toggle(&app, id, user.id, Flag::Pinned).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/favorite",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "The note, added to or taken from favorites", body = Note)),
+    security(("clerk" = []))
+)]
+pub async fn favorite(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Note> {
+    toggle(&app, id, user.id, Flag::Favorite).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/archive",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "The note, archived or restored", body = Note)),
+    security(("clerk" = []))
+)]
+pub async fn archive(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Note> {
+    toggle(&app, id, user.id, Flag::Archived).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::json;
+
+    use super::{apply, Note, NoteVersion, TextEdit};
+    use crate::testing::{body, json, TestApp};
+
+    fn edit(start: usize, end: usize, text: &str) -> TextEdit {
+        TextEdit {
+            start,
+            end,
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn applies_edits() {
+        let edits = vec![edit(6, 11, "there"), edit(0, 0, "> "), edit(11, 11, "!")];
+        assert_eq!(apply("Hello world", edits).unwrap(), "> Hello there!");
+        assert!(apply("Hello", vec![edit(0, 3, ""), edit(2, 4, "")]).is_err());
+        assert!(apply("Hello", vec![edit(4, 9, "")]).is_err());
+        assert!(apply("Café", vec![edit(4, 5, "")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn requires_auth() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let request = Request::get("/notes").body(Body::empty()).unwrap();
+        assert_eq!(app.send(request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn create_update_delete() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let author_id = app.create_user("user_a").await;
+        let res = app
+            .post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Draft", "content": "# Hello" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let note: Note = json(res).await;
+        assert_eq!(note.author_id, author_id);
+
+        let res = app
+            .post_json(
+                &format!("/note/{}", note.id),
+                "user_a",
+                json!({ "title": "Final" }),
+            )
+            .await;
+        let updated: Note = json(res).await;
+        assert_eq!(updated.title, "Final");
+        assert_eq!(updated.content, "# Hello");
+
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        assert_eq!(notes.len(), 1);
+
+        let res = app.delete(&format!("/note/{}", note.id), "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        assert!(notes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pins_favorites_and_archives() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let mut ids = Vec::new();
+        let mut updated = Vec::new();
+        for title in ["One", "Two", "Three"] {
+            let note: Note = json(
+                app.post_json("/notes", "user_a", json!({ "title": title, "content": "" }))
+                    .await,
+            )
+            .await;
+            ids.push(note.id);
+            updated.push(note.updated_at);
+        }
+        let titles =
+            |notes: Vec<Note>| notes.into_iter().map(|note| note.title).collect::<Vec<_>>();
+
+        let pinned: Note = json(
+            app.post_json(&format!("/note/{}/pin", ids[0]), "user_a", json!({}))
+                .await,
+        )
+        .await;
+        assert!(pinned.pinned);
+        // Flags aren't edits, so the note keeps its place among the others.
+        assert_eq!(pinned.updated_at, updated[0]);
+        app.post_json(&format!("/note/{}/archive", ids[1]), "user_a", json!({}))
+            .await;
+        app.post_json(&format!("/note/{}/favorite", ids[2]), "user_a", json!({}))
+            .await;
+        let res = app
+            .post_json(&format!("/note/{}/pin", ids[1]), "user_b", json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        assert_eq!(titles(notes), ["One", "Three"]);
+        let notes: Vec<Note> = json(app.get("/notes?archived=true", "user_a").await).await;
+        assert_eq!(titles(notes), ["One", "Three", "Two"]);
+        let notes: Vec<Note> = json(app.get("/notes?favorite=true", "user_a").await).await;
+        assert_eq!(titles(notes), ["Three"]);
+
+        let unpinned: Note = json(
+            app.post_json(&format!("/note/{}/pin", ids[0]), "user_a", json!({}))
+                .await,
+        )
+        .await;
+        assert!(!unpinned.pinned);
+        assert!(!unpinned.favorite && !unpinned.archived);
+        assert_eq!(unpinned.updated_at, updated[0]);
+    }
+
+    #[tokio::test]
+    async fn patches_content_against_a_version() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Draft", "content": "# Plan\n\nGo" }),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(note.version, 1);
+        let uri = format!("/note/{}", note.id);
+
+        let res = app
+            .patch_json(
+                &uri,
+                "user_a",
+                json!({ "version": 1, "edits": [{ "start": 10, "end": 10, "text": " outside" }] }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let version: NoteVersion = json(res).await;
+        assert_eq!(version.version, 2);
+        let note: Note = json(app.get(&uri, "user_a").await).await;
+        assert_eq!(note.content, "# Plan\n\nGo outside");
+        assert_eq!(note.word_count, 3);
+
+        let res = app
+            .patch_json(
+                &uri,
+                "user_a",
+                json!({ "version": 1, "edits": [{ "start": 0, "end": 1, "text": "" }] }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = app
+            .patch_json(
+                &uri,
+                "user_a",
+                json!({ "version": 2, "edits": [{ "start": 0, "end": 99, "text": "" }] }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // Saving the same content, or only the title, keeps the version.
+        let note: Note = json(
+            app.post_json(
+                &uri,
+                "user_a",
+                json!({ "content": note.content, "title": "Plan" }),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(note.version, 2);
+        let note: Note = json(
+            app.post_json(&uri, "user_a", json!({ "content": "New" }))
+                .await,
+        )
+        .await;
+        assert_eq!(note.version, 3);
+    }
+
+    #[tokio::test]
+    async fn notes_are_private() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Mine", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+
+        let notes: Vec<Note> = json(app.get("/notes", "user_b").await).await;
+        assert!(notes.is_empty());
+        let res = app.delete(&format!("/note/{}", note.id), "user_b").await;
+        assert_ne!(res.status(), StatusCode::OK);
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        assert_eq!(notes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn renders_with_photos_inlined() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        app.post_file("/photos", "user_a", "cat.png", "image/png", b"png")
+            .await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Cat", "content": "# Cat\n\n![cat](cat.png)\n\n<script>x</script>" }),
+            )
+            .await,
+        )
+        .await;
+
+        let res = app
+            .get(&format!("/note/{}/render", note.id), "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let html = String::from_utf8(body(res).await.to_vec()).unwrap();
+        assert!(html.contains("<h1>Cat</h1>"));
+        assert!(html.contains(r#"src="data:image/png;base64,cG5n""#));
+        assert!(!html.contains("script"));
+
+        let res = app
+            .get(&format!("/note/{}/render", note.id), "user_b")
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,181 @@
+use axum::Router;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    attachment, clerk, export, health, import, journal, link, location, metadata, note, outline,
+    photo, photo_ref, share, stats, telemetry, template, token, weather, wikilink, AppState,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Editor API"),
+    paths(
+        note::get_all,
+        note::create,
+        note::get,
+        note::update,
+        note::patch,
+        note::delete,
+        note::render,
+        outline::get,
+        note::pin,
+        note::favorite,
+        note::archive,
+        attachment::get_all,
+        attachment::upload,
+        attachment::download,
+        attachment::delete,
+        journal::daily,
+        stats::get,
+        wikilink::backlinks,
+        wikilink::outgoing,
+        export::notes,
+        import::notes,
+        metadata::notes,
+        share::get_all,
+        share::create,
+        share::delete,
+        share::shared_with_me,
+        link::get_all,
+        link::create,
+        link::revoke,
+        link::view,
+        link::unlock,
+        link::raw,
+        template::get_all,
+        template::create,
+        template::update,
+        template::delete,
+        template::instantiate,
+        weather::get,
+        location::get,
+        location::save,
+        location::delete,
+        photo::get_all,
+        photo::upload,
+        photo::view,
+        photo_ref::notes,
+        photo_ref::gc,
+        metadata::photos,
+        metadata::import_captions,
+        token::get_all,
+        token::create,
+        token::revoke,
+        clerk::post_webhook,
+        health::healthz,
+        health::readyz,
+        health::version,
+        telemetry::get,
+    ),
+    components(schemas(weather::TemperatureUnit, export::ExportFormat, stats::StatsPeriod)),
+    modifiers(&ClerkAuth)
+)]
+pub struct ApiDoc;
+
+struct ClerkAuth;
+
+impl Modify for ClerkAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "clerk",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "Clerk session JWT, or a personal access token starting with `edt_`",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Serves the spec at `/openapi.json` and Swagger UI at `/docs`.
+pub fn docs() -> Router<AppState> {
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, StatusCode},
+    };
+    use tower::ServiceExt;
+    use utoipa::openapi::PathItem;
+
+    use super::*;
+    use crate::{
+        build_app,
+        testing::{offline_state, request},
+    };
+
+    fn documents(item: &PathItem, method: &Method) -> bool {
+        match *method {
+            Method::GET => item.get.is_some(),
+            Method::POST => item.post.is_some(),
+            Method::PUT => item.put.is_some(),
+            Method::PATCH => item.patch.is_some(),
+            Method::DELETE => item.delete.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Every documented operation has to reach a handler, and every other
+    /// method on a documented path has to be rejected by the router.
+    #[tokio::test]
+    async fn spec_matches_router() {
+        let app = build_app(offline_state());
+        let spec = ApiDoc::openapi();
+        assert!(!spec.paths.paths.is_empty());
+        for (path, item) in spec.paths.paths.iter() {
+            let uri = path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "00000000-0000-0000-0000-000000000000"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            for method in [
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ] {
+                let documented = documents(item, &method);
+                let request = request(method.clone(), &uri, "user_test")
+                    .body(Body::empty())
+                    .unwrap();
+                let status = app.clone().oneshot(request).await.unwrap().status();
+                if documented {
+                    assert!(
+                        status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                        "{} {} is documented but not routed",
+                        method,
+                        path
+                    );
+                } else {
+                    assert_eq!(
+                        status,
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{} {} is routed but not documented",
+                        method,
+                        path
+                    );
+                }
+            }
+        }
+    }
+}
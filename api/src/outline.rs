@@ -0,0 +1,165 @@
+//! A note's headings as a tree, for a table of contents that can jump to
+//! each section.
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::query_scalar;
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::JsonRes,
+    render::{headings, Heading},
+    share::{self, Access},
+    AppState,
+};
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OutlineHeading {
+    /// 1 for `#` through 6 for `######`.
+    pub level: u8,
+    pub text: String,
+    /// An anchor for the heading, unique within the note.
+    pub slug: String,
+    /// Byte offset of the heading in the note's content.
+    pub start: usize,
+    /// Byte offset just past the heading's own line.
+    pub end: usize,
+    /// Byte offset where the next heading of the same or a higher level
+    /// starts, or the end of the content.
+    pub section_end: usize,
+    /// Headings of lower levels in its section.
+    #[schema(no_recursion)]
+    pub children: Vec<OutlineHeading>,
+}
+
+/// Moves the top heading of `open` into its parent, or `roots` when it has
+/// none, with its section ending at `end`.
+fn close(open: &mut Vec<OutlineHeading>, roots: &mut Vec<OutlineHeading>, end: usize) {
+    let Some(mut heading) = open.pop() else {
+        return;
+    };
+    heading.section_end = end;
+    match open.last_mut() {
+        Some(parent) => parent.children.push(heading),
+        None => roots.push(heading),
+    }
+}
+
+/// Nests `headings` under the nearest heading of a higher level before them.
+/// Skipped levels are fine, so a `###` right under a `#` is its child.
+fn tree(headings: Vec<Heading>, len: usize) -> Vec<OutlineHeading> {
+    let mut roots = Vec::new();
+    let mut open: Vec<OutlineHeading> = Vec::new();
+    for heading in headings {
+        while open.last().is_some_and(|top| top.level >= heading.level) {
+            close(&mut open, &mut roots, heading.span.start);
+        }
+        open.push(OutlineHeading {
+            level: heading.level,
+            text: heading.text,
+            slug: heading.slug,
+            start: heading.span.start,
+            end: heading.span.end,
+            section_end: len,
+            children: Vec::new(),
+        });
+    }
+    while !open.is_empty() {
+        close(&mut open, &mut roots, len);
+    }
+    roots
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/outline",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "The note's top level headings, with the rest nested under them", body = Vec<OutlineHeading>)),
+    security(("clerk" = []))
+)]
+pub async fn get(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<OutlineHeading>> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let content = query_scalar!("select content from note where id = $1", id)
+        .fetch_one(&app.db)
+        .await?;
+    Ok(Json(tree(headings(&content), content.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::{tree, OutlineHeading};
+    use crate::{
+        note::Note,
+        render::headings,
+        testing::{json, TestApp},
+    };
+
+    fn shape(headings: &[OutlineHeading]) -> String {
+        headings
+            .iter()
+            .map(|heading| match heading.children.is_empty() {
+                true => heading.slug.clone(),
+                false => format!("{}({})", heading.slug, shape(&heading.children)),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn nests_headings() {
+        let content = "Intro\n\n# A\n\n### A deep\n\n## A two\n\n# B\n\n## B two\n\n# A\n";
+        let outline = tree(headings(content), content.len());
+        assert_eq!(shape(&outline), "a(a-deep a-two) b(b-two) a-1");
+        let a = &outline[0];
+        assert_eq!(&content[a.start..a.end], "# A\n");
+        assert_eq!(
+            &content[a.start..a.section_end],
+            "# A\n\n### A deep\n\n## A two\n\n"
+        );
+        assert_eq!(a.children[0].section_end, content.find("## A two").unwrap());
+        assert_eq!(outline[2].section_end, content.len());
+    }
+
+    #[tokio::test]
+    async fn serves_outlines() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Plan", "content": "# Goals\n\n## Soon\n" }),
+            )
+            .await,
+        )
+        .await;
+        let uri = format!("/note/{}/outline", note.id);
+        let res = app.get(&uri, "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let outline: Vec<OutlineHeading> = json(res).await;
+        assert_eq!(shape(&outline), "goals(soon)");
+        assert_eq!(outline[0].children[0].start, 9);
+
+        let res = app.get(&uri, "user_b").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
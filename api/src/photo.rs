@@ -1,26 +1,32 @@
+use std::collections::HashMap;
+
+use axum::body::Bytes;
 use axum::extract::Path;
-use axum::Extension;
 use axum::{
     extract::{Multipart, State},
+    http::StatusCode,
     response::IntoResponse,
     Json,
 };
+use base64::prelude::*;
 use chrono::{DateTime, Utc};
-use clerk_rs::validators::authorizer::ClerkJwt;
 use serde::{Deserialize, Serialize};
-use sqlx::query_as;
+use sqlx::{query_as, query_scalar};
 use ts_rs::TS;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::clerk::get_user;
 use crate::{
+    auth::CurrentUser,
     error::{AppError, JsonRes},
+    render::photo_refs,
+    storage::Object,
     AppState,
 };
 
 #[derive(TS)]
 #[ts(export)]
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct Photo {
     pub name: String,
     pub caption: String,
@@ -28,11 +34,65 @@ pub struct Photo {
     pub size_b: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// When a note first embedded the photo. Photos never embedded are only
+    /// in the gallery, and aren't collected.
+    pub referenced_at: Option<DateTime<Utc>>,
+}
+
+/// Where a photo is kept in the bucket. Photos are namespaced by author and
+/// apart from attachments, so no name can reach another object.
+pub fn key(author_id: Uuid, name: &str) -> String {
+    format!("photos/{}/{}", author_id, name)
+}
+
+/// Names are a single path segment, so they can be used in keys, URLs and
+/// export archives as they are.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control)
+}
+
+/// The bucket object behind a photo. Photos uploaded before they were
+/// namespaced are still kept under their bare name.
+pub async fn object(app: &AppState, author_id: Uuid, name: &str) -> anyhow::Result<Object> {
+    match app.storage.get(&key(author_id, name)).await {
+        Ok(object) => Ok(object),
+        Err(error) if valid_name(name) => app.storage.get(name).await.map_err(|_| error),
+        Err(error) => Err(error),
+    }
+}
+
+/// Removes a photo's object, wherever it was kept.
+pub async fn remove(app: &AppState, author_id: Uuid, name: &str) -> anyhow::Result<()> {
+    app.storage.delete(&key(author_id, name)).await?;
+    if valid_name(name) {
+        app.storage.delete(name).await?;
+    }
+    Ok(())
+}
+
+/// Multipart body for `upload`. Only used to document the endpoint.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct PhotoUpload {
+    #[schema(format = Binary, content_media_type = "application/octet-stream")]
+    file: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/photos",
+    tag = "photos",
+    request_body(content = PhotoUpload, content_type = "multipart/form-data"),
+    responses((status = 200, body = Photo)),
+    security(("clerk" = []))
+)]
 pub async fn upload(
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
     mut multipart: Multipart,
 ) -> JsonRes<Photo> {
     let file = multipart.next_field().await?.unwrap();
@@ -40,41 +100,60 @@ pub async fn upload(
         .file_name()
         .map(|s| s.to_owned())
         .unwrap_or_else(|| Uuid::new_v4().to_string());
-    let content_type = file.content_type().unwrap();
-    let user = get_user(&app.db, &jwt.sub).await?;
+    let content_type = file.content_type().unwrap().to_owned();
     if content_type.contains("image") {
         let bytes = file.bytes().await?;
-        let size_b = bytes.len() as i64;
-        let body = aws_sdk_s3::primitives::ByteStream::from(bytes);
-        let caption = "";
-        app.s3
-            .put_object()
-            .bucket("editor")
-            .key(name.clone())
-            .body(body)
-            .send()
-            .await?;
-        let photo = query_as!(
-            Photo,
-            "insert into photo (name, caption, author_id, size_b) values ($1, $2, $3, $4) returning *",
-            name,
-            caption,
-            user.id,
-            size_b
-        )
-        .fetch_one(&app.db)
-        .await?;
-        Ok(Json(photo))
+        Ok(Json(
+            store(&app, user.id, &name, bytes, &content_type).await?,
+        ))
     } else {
         Err(anyhow::Error::msg("Only image format is supported").into())
     }
 }
 
+/// Puts an image in the bucket and records it as one of the author's photos.
+pub async fn store(
+    app: &AppState,
+    author_id: Uuid,
+    name: &str,
+    bytes: Bytes,
+    content_type: &str,
+) -> Result<Photo, AppError> {
+    if !valid_name(name) {
+        return Err(AppError::WithStatus(
+            StatusCode::BAD_REQUEST,
+            anyhow::Error::msg("Photo names can't contain slashes or be . or .."),
+        ));
+    }
+    let size_b = bytes.len() as i64;
+    let caption = "";
+    app.storage
+        .put(&key(author_id, name), bytes, Some(content_type))
+        .await?;
+    let photo = query_as!(
+        Photo,
+        "insert into photo (name, caption, author_id, size_b) values ($1, $2, $3, $4) returning *",
+        name,
+        caption,
+        author_id,
+        size_b
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(photo)
+}
+
+#[utoipa::path(
+    get,
+    path = "/photos",
+    tag = "photos",
+    responses((status = 200, body = Vec<Photo>)),
+    security(("clerk" = []))
+)]
 pub async fn get_all(
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
 ) -> JsonRes<Vec<Photo>> {
-    let user = get_user(&app.db, &jwt.sub).await?;
     let photos = query_as!(Photo, "select * from photo where author_id = $1", user.id)
         .fetch_all(&app.db)
         .await?;
@@ -85,9 +164,8 @@ pub async fn get_all(
 pub async fn delete(
     Path(id): Path<String>,
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
 ) -> JsonRes<Photo> {
-    let user = get_user(&app.db, &jwt.sub).await?;
     let document = query_as!(
         Photo,
         "delete from photo where name = $1 and author_id = $2 returning *",
@@ -107,13 +185,71 @@ pub async fn get(Path(id): Path<String>, State(app): State<AppState>) -> JsonRes
     Ok(Json(document))
 }
 
+/// The objects behind those of `names` that belong to the author. Photos the
+/// bucket can't serve are logged and left out.
+pub async fn load(
+    app: &AppState,
+    author_id: Uuid,
+    names: &[String],
+) -> Result<Vec<(String, Object)>, AppError> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let owned = query_scalar!(
+        "select name from photo where author_id = $1 and name = any($2)",
+        author_id,
+        names
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let mut objects = Vec::new();
+    for name in owned {
+        match object(app, author_id, &name).await {
+            Ok(object) => objects.push((name, object)),
+            Err(error) => tracing::warn!("Couldn't load photo {}: {}", name, error),
+        }
+    }
+    Ok(objects)
+}
+
+pub fn data_uri(object: &Object) -> String {
+    format!(
+        "data:{};base64,{}",
+        object
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream"),
+        BASE64_STANDARD.encode(&object.bytes)
+    )
+}
+
+/// The author's photos that `markdown` embeds, as data URIs, so rendered HTML
+/// shows them without a session.
+pub async fn inline(
+    app: &AppState,
+    author_id: Uuid,
+    markdown: &str,
+) -> Result<HashMap<String, String>, AppError> {
+    let objects = load(app, author_id, &photo_refs(markdown)).await?;
+    Ok(objects
+        .iter()
+        .map(|(name, object)| (name.clone(), data_uri(object)))
+        .collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/photos/{name}",
+    tag = "photos",
+    params(("name" = String, Path, description = "Photo name")),
+    responses((status = 200, description = "The image bytes", content_type = "image/*")),
+    security(("clerk" = []))
+)]
 pub async fn view(
     Path(name): Path<String>,
     State(app): State<AppState>,
-    Extension(jwt): Extension<ClerkJwt>,
+    CurrentUser(user): CurrentUser,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = get_user(&app.db, &jwt.sub).await?;
-
     // Verify ownership
     let _photo = query_as!(
         Photo,
@@ -124,23 +260,9 @@ pub async fn view(
     .fetch_one(&app.db)
     .await?;
 
-    let object = app
-        .s3
-        .get_object()
-        .bucket("editor")
-        .key(name)
-        .send()
-        .await
-        .map_err(|e| anyhow::Error::new(e))?;
-
-    let bytes = object
-        .body
-        .collect()
-        .await
-        .map_err(|e| anyhow::Error::new(e))?
-        .into_bytes();
-
-    let body = axum::body::Body::from(bytes);
+    let object = object(&app, user.id, &name).await?;
+    let content_length = object.bytes.len();
+    let body = axum::body::Body::from(object.bytes);
 
     let mut headers = axum::http::HeaderMap::new();
     if let Some(content_type) = object.content_type {
@@ -149,12 +271,10 @@ pub async fn view(
             content_type.parse().unwrap(),
         );
     }
-    if let Some(content_length) = object.content_length {
-        headers.insert(
-            axum::http::header::CONTENT_LENGTH,
-            content_length.to_string().parse().unwrap(),
-        );
-    }
+    headers.insert(
+        axum::http::header::CONTENT_LENGTH,
+        content_length.to_string().parse().unwrap(),
+    );
     headers.insert(
         axum::http::header::CACHE_CONTROL,
         "private, max-age=2592000".parse().unwrap(),
@@ -162,3 +282,82 @@ pub async fn view(
 
     Ok((headers, body))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::CONTENT_TYPE, StatusCode};
+
+    use super::Photo;
+    use crate::{
+        storage::Storage,
+        testing::{body, json, TestApp},
+    };
+
+    #[tokio::test]
+    async fn upload_and_view() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let res = app
+            .post_file(
+                "/photos",
+                "user_a",
+                "cat.png",
+                "image/png",
+                b"not really a png",
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let photo: Photo = json(res).await;
+        assert_eq!(photo.name, "cat.png");
+        assert_eq!(photo.size_b, 16);
+
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert_eq!(photos.len(), 1);
+
+        let res = app.get("/photos/cat.png", "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(&body(res).await[..], b"not really a png");
+
+        let res = app.get("/photos/cat.png", "user_b").await;
+        assert_ne!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn keeps_photos_apart_from_other_objects() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let author = app.create_user("user_a").await;
+        for name in ["..", "attachments/x", "attachments\\x"] {
+            let res = app
+                .post_file("/photos", "user_a", name, "image/png", b"png")
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", name);
+        }
+        app.post_file("/photos", "user_a", "cat.png", "image/png", b"png")
+            .await;
+        let Storage::Memory(objects) = &app.state.storage else {
+            unreachable!()
+        };
+        let keys: Vec<String> = objects.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, [format!("photos/{}/cat.png", author)]);
+    }
+
+    #[tokio::test]
+    async fn rejects_non_images() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let res = app
+            .post_file("/photos", "user_a", "notes.txt", "text/plain", b"hello")
+            .await;
+        assert_ne!(res.status(), StatusCode::OK);
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert!(photos.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+//! Which notes embed which photos. References are recorded whenever a note is
+//! saved, so photos no note uses any more can be found and deleted.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgPool};
+use ts_rs::TS;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    photo,
+    render::photo_refs,
+    wikilink::NoteLink,
+    AppState,
+};
+
+/// How often unused photos are collected for everyone.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, IntoParams)]
+pub struct CollectQuery {
+    /// Only list the photos that would be deleted.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PhotoCollection {
+    /// Photos no note embeds, deleted unless this was a dry run.
+    unused: Vec<String>,
+    deleted: bool,
+}
+
+/// Records the author's photos `content` embeds, replacing what was recorded
+/// before, and marks them as having been embedded. Notes only show their
+/// author's photos, so others don't count.
+pub async fn sync(
+    db: &PgPool,
+    note_id: Uuid,
+    author_id: Uuid,
+    content: &str,
+) -> Result<(), AppError> {
+    let names = photo_refs(content);
+    let mut tx = db.begin().await?;
+    query!("delete from photo_ref where note_id = $1", note_id)
+        .execute(&mut *tx)
+        .await?;
+    query!(
+        "insert into photo_ref (note_id, photo_name)
+        select $1, name from photo where author_id = $2 and name = any($3)",
+        note_id,
+        author_id,
+        &names
+    )
+    .execute(&mut *tx)
+    .await?;
+    query!(
+        "update photo set referenced_at = now()
+        where author_id = $1 and name = any($2) and referenced_at is null",
+        author_id,
+        &names
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Finds the author's photos that notes used to embed and none do any more,
+/// and deletes them unless `dry_run`. Photos that were only ever in the
+/// gallery, or that a template embeds, are kept. Notes are scanned again
+/// first, to catch photos uploaded after the note that uses them was saved.
+/// Photos uploaded in the last day are kept, as they may be about to be used.
+async fn collect(app: &AppState, author_id: Uuid, dry_run: bool) -> Result<Vec<String>, AppError> {
+    let notes = query!(
+        "select id, content from note where author_id = $1",
+        author_id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    for note in notes {
+        sync(&app.db, note.id, author_id, &note.content).await?;
+    }
+    let templates = query_scalar!(
+        "select content from note_template where author_id = $1",
+        author_id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let templated: Vec<String> = templates
+        .iter()
+        .flat_map(|content| photo_refs(content))
+        .collect();
+    if dry_run {
+        let unused = query_scalar!(
+            "select name from photo
+            where author_id = $1 and created_at < now() - interval '1 day'
+            and referenced_at is not null and name <> all($2)
+            and not exists (select 1 from photo_ref where photo_ref.photo_name = photo.name)
+            order by name",
+            author_id,
+            &templated
+        )
+        .fetch_all(&app.db)
+        .await?;
+        return Ok(unused);
+    }
+    let mut deleted = query_scalar!(
+        "delete from photo
+        where author_id = $1 and created_at < now() - interval '1 day'
+        and referenced_at is not null and name <> all($2)
+        and not exists (select 1 from photo_ref where photo_ref.photo_name = photo.name)
+        returning name",
+        author_id,
+        &templated
+    )
+    .fetch_all(&app.db)
+    .await?;
+    deleted.sort();
+    for name in &deleted {
+        if let Err(error) = photo::remove(app, author_id, name).await {
+            tracing::warn!("Couldn't remove photo {}: {}", name, error);
+        }
+    }
+    Ok(deleted)
+}
+
+/// Collects every user's unused photos once a day, when `collect_photos` is
+/// set.
+pub fn spawn_collection(app: &AppState) {
+    if !app.config.collect_photos {
+        return;
+    }
+    let app = app.clone();
+    app.jobs.clone().spawn(async move {
+        let start = tokio::time::Instant::now() + COLLECT_INTERVAL;
+        let mut interval = tokio::time::interval_at(start, COLLECT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => collect_all(&app).await,
+                _ = app.jobs.cancelled() => break,
+            }
+        }
+    });
+}
+
+async fn collect_all(app: &AppState) {
+    let authors = match query_scalar!("select distinct author_id from photo")
+        .fetch_all(&app.db)
+        .await
+    {
+        Ok(authors) => authors,
+        Err(error) => {
+            tracing::warn!("Couldn't list photo authors: {}", error);
+            return;
+        }
+    };
+    for author_id in authors {
+        match collect(app, author_id, false).await {
+            Ok(deleted) if !deleted.is_empty() => {
+                tracing::info!("Deleted {} unused photos of {}", deleted.len(), author_id)
+            }
+            Ok(_) => {}
+            Err(AppError::WithStatus(_, error) | AppError::Internal(error)) => {
+                tracing::warn!("Couldn't collect photos of {}: {}", author_id, error)
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/photos/{name}/notes",
+    tag = "photos",
+    params(("name" = String, Path, description = "Photo name")),
+    responses(
+        (status = 200, description = "Notes that embed the photo", body = Vec<NoteLink>),
+        (status = 404, description = "No such photo")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn notes(
+    Path(name): Path<String>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<NoteLink>> {
+    let owned = query_scalar!(
+        "select exists (select 1 from photo where name = $1 and author_id = $2)",
+        name,
+        user.id
+    )
+    .fetch_one(&app.db)
+    .await?;
+    if owned != Some(true) {
+        return Err(AppError::WithStatus(
+            StatusCode::NOT_FOUND,
+            anyhow::Error::msg("No such photo"),
+        ));
+    }
+    let notes = query_as!(
+        NoteLink,
+        "select note.id, note.title, note.updated_at
+        from photo_ref join note on note.id = photo_ref.note_id
+        where photo_ref.photo_name = $1
+        order by note.updated_at desc",
+        name
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(notes))
+}
+
+#[utoipa::path(
+    post,
+    path = "/photos/gc",
+    tag = "photos",
+    params(CollectQuery),
+    responses((status = 200, body = PhotoCollection)),
+    security(("clerk" = []))
+)]
+pub async fn gc(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<CollectQuery>,
+) -> JsonRes<PhotoCollection> {
+    let unused = collect(&app, user.id, query.dry_run).await?;
+    Ok(Json(PhotoCollection {
+        unused,
+        deleted: !query.dry_run,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use sqlx::query;
+
+    use super::PhotoCollection;
+    use crate::{
+        note::Note,
+        photo::Photo,
+        testing::{json, TestApp},
+        wikilink::NoteLink,
+    };
+
+    #[tokio::test]
+    async fn tracks_and_collects_photos() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        for name in ["cat.png", "dog.png", "late.png"] {
+            app.post_file("/photos", "user_a", name, "image/png", b"png")
+                .await;
+        }
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Pets", "content": "![](/photos/cat.png) ![](late.png)" }),
+            )
+            .await,
+        )
+        .await;
+        // Written before the photo existed, so only found by collecting.
+        app.post_json(
+            "/notes",
+            "user_a",
+            json!({ "title": "Soon", "content": "![](soon.png)" }),
+        )
+        .await;
+        app.post_file("/photos", "user_a", "soon.png", "image/png", b"png")
+            .await;
+
+        let res = app.get("/photos/cat.png/notes", "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let notes: Vec<NoteLink> = json(res).await;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, note.id);
+        let res = app.get("/photos/cat.png/notes", "user_b").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        app.post_json(
+            &format!("/note/{}", note.id),
+            "user_a",
+            json!({ "content": "![](/photos/cat.png)" }),
+        )
+        .await;
+        // Photos from the last day are kept.
+        let report: PhotoCollection =
+            json(app.post_json("/photos/gc", "user_a", json!({})).await).await;
+        assert!(report.unused.is_empty());
+
+        query!("update photo set created_at = now() - interval '2 days'")
+            .execute(&app.state.db)
+            .await
+            .unwrap();
+        let report: PhotoCollection = json(
+            app.post_json("/photos/gc?dry_run=true", "user_a", json!({}))
+                .await,
+        )
+        .await;
+        // dog.png was never embedded, so it stays in the gallery.
+        assert_eq!(report.unused, ["late.png"]);
+        assert!(!report.deleted);
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert_eq!(photos.len(), 4);
+
+        let report: PhotoCollection =
+            json(app.post_json("/photos/gc", "user_a", json!({})).await).await;
+        assert_eq!(report.unused, ["late.png"]);
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        let mut names: Vec<_> = photos.into_iter().map(|photo| photo.name).collect();
+        names.sort();
+        assert_eq!(names, ["cat.png", "dog.png", "soon.png"]);
+        let res = app.get("/photos/late.png", "user_a").await;
+        assert_ne!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn keeps_gallery_and_template_photos() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        for name in ["gallery.png", "header.png"] {
+            app.post_file("/photos", "user_a", name, "image/png", b"png")
+                .await;
+        }
+        app.post_json(
+            "/templates",
+            "user_a",
+            json!({ "name": "Day", "title": "Day", "content": "<img src=\"header.png\">" }),
+        )
+        .await;
+        // Embedded once, then the note moved on.
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Day", "content": "![[header.png]]" }),
+            )
+            .await,
+        )
+        .await;
+        app.post_json(
+            &format!("/note/{}", note.id),
+            "user_a",
+            json!({ "content": "" }),
+        )
+        .await;
+        query!("update photo set created_at = now() - interval '2 days'")
+            .execute(&app.state.db)
+            .await
+            .unwrap();
+
+        let report: PhotoCollection =
+            json(app.post_json("/photos/gc", "user_a", json!({})).await).await;
+        assert!(report.unused.is_empty());
+        let photos: Vec<Photo> = json(app.get("/photos", "user_a").await).await;
+        assert_eq!(photos.len(), 2);
+        let res = app.get("/photos/gallery.png", "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{auth::Identity, error::AppError, AppState};
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub struct Budget {
+    name: &'static str,
+    capacity: u32,
+    period: Duration,
+}
+
+impl Budget {
+    const fn per_minute(name: &'static str, capacity: u32) -> Self {
+        Budget {
+            name,
+            capacity,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// The value of `key` in a query string, empty when it has none.
+fn param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (name == key).then_some(value)
+    })
+}
+
+/// `/weather` calls two paid APIs on a cache miss and uploads are expensive,
+/// so they get their own, tighter buckets. Notes that get the weather filled
+/// in share the weather bucket. Share links are public and may be password
+/// protected, so they're limited to slow down guessing. Everything else
+/// shares one.
+fn budget(method: &Method, path: &str, query: &str) -> Budget {
+    let weather = Budget::per_minute("weather", 20);
+    match (method, path) {
+        (&Method::GET, "/weather") => weather,
+        (&Method::POST, "/notes/from-template/:id") if param(query, "lat").is_some() => weather,
+        (&Method::GET, "/notes/daily") if param(query, "weather") == Some("true") => weather,
+        (&Method::POST, "/photos" | "/notes/import" | "/note/:id/attachments") => {
+            Budget::per_minute("upload", 10)
+        }
+        (&Method::POST, "/clerk-webhook") => Budget::per_minute("webhook", 60),
+        (_, path) if path.starts_with("/s/") => Budget::per_minute("share", 30),
+        _ => Budget::per_minute("default", 300),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    period: Duration,
+}
+
+pub struct Decision {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(&'static str, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn check(&self, budget: Budget, key: String) -> Decision {
+        let now = Instant::now();
+        let rate = budget.refill_rate();
+        let capacity = budget.capacity as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((budget.name, key)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            period: budget.period,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.;
+        if allowed {
+            bucket.tokens -= 1.;
+        }
+        let reset = if allowed {
+            (capacity - bucket.tokens) / rate
+        } else {
+            (1. - bucket.tokens) / rate
+        };
+        Decision {
+            allowed,
+            limit: budget.capacity,
+            remaining: bucket.tokens.floor() as u32,
+            reset: reset.ceil() as u64,
+        }
+    }
+
+    /// Drops buckets that have been idle long enough to be full again.
+    fn prune(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.updated) < bucket.period);
+    }
+}
+
+pub fn spawn_pruning(app: &AppState) {
+    let jobs = app.jobs.clone();
+    let limiter = app.rate_limiter.clone();
+    app.jobs.spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => limiter.prune(),
+                _ = jobs.cancelled() => break,
+            }
+        }
+    });
+}
+
+pub async fn limit(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    let budget = budget(
+        request.method(),
+        path,
+        request.uri().query().unwrap_or_default(),
+    );
+    let decision = app
+        .rate_limiter
+        .check(budget, client_key(&request, &app.config.trusted_proxies));
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        let mut response = AppError::WithStatus(
+            StatusCode::TOO_MANY_REQUESTS,
+            anyhow::Error::msg("Too many requests"),
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from(decision.reset));
+        response
+    };
+    set_headers(response.headers_mut(), &decision);
+    response
+}
+
+/// Signed in users are limited by their Clerk id, whether they use a session
+/// or a token, and everyone else by address. `X-Forwarded-For` is only
+/// believed when the peer is one of our proxies, and then only up to the
+/// last address a proxy of ours didn't add, since anything before that the
+/// client could have written itself.
+fn client_key(request: &Request, trusted_proxies: &[IpAddr]) -> String {
+    if let Some(identity) = request.extensions().get::<Identity>() {
+        return format!("user:{}", identity.clerk_id());
+    }
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let mut ip = peer;
+    if peer.is_some_and(|peer| trusted_proxies.contains(&peer)) {
+        let forwarded = request
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            ip = Some(hop);
+            if !trusted_proxies.contains(&hop) {
+                break;
+            }
+        }
+    }
+    format!("ip:{}", ip.map(|ip| ip.to_string()).unwrap_or_default())
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use axum::{body::Body, extract::ConnectInfo};
+
+    use axum::http::Method;
+
+    use super::{budget, client_key};
+    use crate::testing::request;
+
+    fn key(peer: &str, forwarded: Option<&str>) -> String {
+        let proxies: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let mut builder = request(Method::GET, "/s/token", "");
+        if let Some(forwarded) = forwarded {
+            builder = builder.header("x-forwarded-for", forwarded);
+        }
+        let mut request = builder.body(Body::empty()).unwrap();
+        let peer: SocketAddr = format!("{}:443", peer).parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        client_key(&request, &proxies)
+    }
+
+    #[test]
+    fn limits_uploads_tighter() {
+        assert_eq!(budget(&Method::POST, "/photos", "").name, "upload");
+        assert_eq!(budget(&Method::POST, "/notes/import", "").name, "upload");
+        assert_eq!(
+            budget(&Method::POST, "/note/:id/attachments", "").name,
+            "upload"
+        );
+        assert_eq!(budget(&Method::GET, "/notes", "").name, "default");
+    }
+
+    #[test]
+    fn charges_weather_lookups_to_the_weather_budget() {
+        let template = "/notes/from-template/:id";
+        assert_eq!(budget(&Method::POST, template, "").name, "default");
+        assert_eq!(
+            budget(&Method::POST, template, "lat=1&lon=2").name,
+            "weather"
+        );
+        assert_eq!(budget(&Method::GET, "/notes/daily", "").name, "default");
+        assert_eq!(
+            budget(&Method::GET, "/notes/daily", "date=2025-01-01&weather=true").name,
+            "weather"
+        );
+    }
+
+    #[test]
+    fn only_trusts_forwarded_for_from_proxies() {
+        assert_eq!(key("203.0.113.9", None), "ip:203.0.113.9");
+        // Anyone else can claim to be forwarding for someone.
+        assert_eq!(key("203.0.113.9", Some("198.51.100.1")), "ip:203.0.113.9");
+        assert_eq!(key("10.0.0.1", Some("198.51.100.1")), "ip:198.51.100.1");
+        // Addresses before the last one our proxies didn't add are the
+        // client's own.
+        assert_eq!(
+            key("10.0.0.1", Some("192.0.2.7, 198.51.100.1, 10.0.0.2")),
+            "ip:198.51.100.1"
+        );
+        assert_eq!(key("10.0.0.1", Some("garbage")), "ip:10.0.0.1");
+    }
+}
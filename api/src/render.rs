@@ -0,0 +1,546 @@
+//! Server side Markdown rendering, for pages served outside the web app.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::LazyLock,
+};
+
+use ammonia::Builder;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag, TagEnd};
+use syntect::{highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet};
+
+const STYLE: &str = "body{margin:0;font-family:system-ui,sans-serif;line-height:1.6;color:#1f2328}\
+main{max-width:46rem;margin:0 auto;padding:2rem 1rem}\
+pre{overflow-x:auto;padding:1rem;background:#f6f8fa;border-radius:6px}\
+code{font-size:.9em}img{max-width:100%}\
+table{border-collapse:collapse}td,th{border:1px solid #d0d7de;padding:.25rem .75rem}\
+.footnote-definition{font-size:.9em}";
+
+/// Escaped in image sources so any photo name survives a round trip.
+const SRC: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'%')
+    .add(b'(')
+    .add(b')')
+    .add(b'<')
+    .add(b'>');
+
+const THEME: &str = "InspiredGitHub";
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+}
+
+/// The photo an image source points at, the same way the web app resolves
+/// them: bare names and `/photos/<name>` are photos, anything with a scheme
+/// isn't.
+pub fn photo_name(src: &str) -> Option<String> {
+    if src.is_empty() || src.contains(':') {
+        return None;
+    }
+    let name = src
+        .strip_prefix("/photos/")
+        .or_else(|| src.strip_prefix("./"))
+        .unwrap_or(src);
+    Some(percent_decode_str(name).decode_utf8_lossy().into_owned())
+}
+
+/// The `src` of each `<img>` tag in `html`.
+fn img_sources(html: &str) -> Vec<&str> {
+    let lower = html.to_ascii_lowercase();
+    let mut sources = Vec::new();
+    for (start, _) in lower.match_indices("<img") {
+        let end = lower[start..]
+            .find('>')
+            .map_or(html.len(), |end| start + end);
+        let Some(attr) = lower[start..end]
+            .match_indices("src=")
+            .map(|(i, _)| start + i)
+            .find(|&i| lower[..i].ends_with(char::is_whitespace))
+        else {
+            continue;
+        };
+        let value = &html[attr + 4..end];
+        let src = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next(),
+            _ => value
+                .split(char::is_whitespace)
+                .next()
+                .map(|src| src.trim_end_matches('/')),
+        };
+        sources.extend(src.filter(|src| !src.is_empty()));
+    }
+    sources
+}
+
+/// Names of the photos a note embeds, in order of first use. Images count
+/// whether they're written in Markdown, as `![[name]]` or as `<img>` tags.
+pub fn photo_refs(markdown: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+    for event in Parser::new_ext(markdown, options() | Options::ENABLE_WIKILINKS) {
+        match event {
+            Event::Start(Tag::Image { dest_url, .. }) => names.extend(photo_name(&dest_url)),
+            Event::Html(html) | Event::InlineHtml(html) => {
+                names.extend(img_sources(&html).into_iter().filter_map(photo_name))
+            }
+            _ => {}
+        }
+    }
+    names.retain(|name| seen.insert(name.clone()));
+    names
+}
+
+/// A link or image in a note.
+pub struct Reference<'a> {
+    pub image: bool,
+    /// Written Obsidian style, as `[[dest|label]]` or `![[dest]]`.
+    pub wiki: bool,
+    pub dest: CowStr<'a>,
+    /// The text after `|` in a wiki link.
+    pub label: Option<&'a str>,
+    /// Where the destination is written, or the whole of a wiki link.
+    pub span: Range<usize>,
+}
+
+/// The inline links and images in `markdown`, wiki links included. Reference
+/// style links keep their destination elsewhere and are left out.
+pub fn references(markdown: &str) -> Vec<Reference<'_>> {
+    let parser = Parser::new_ext(markdown, options() | Options::ENABLE_WIKILINKS);
+    let mut references = Vec::new();
+    for (event, range) in parser.into_offset_iter() {
+        let (image, link_type, dest) = match event {
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                ..
+            }) => (true, link_type, dest_url),
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                ..
+            }) => (false, link_type, dest_url),
+            _ => continue,
+        };
+        let source = &markdown[range.clone()];
+        match link_type {
+            LinkType::WikiLink { .. } => {
+                let inner = source
+                    .trim_start_matches('!')
+                    .trim_start_matches("[[")
+                    .trim_end_matches("]]");
+                references.push(Reference {
+                    image,
+                    wiki: true,
+                    dest,
+                    label: inner.split_once('|').map(|(_, label)| label),
+                    span: range,
+                });
+            }
+            LinkType::Inline => {
+                let Some(start) = [format!("(<{}", dest), format!("({}", dest)]
+                    .iter()
+                    .find_map(|prefix| source.rfind(prefix.as_str()).map(|i| i + prefix.len()))
+                    .map(|end| range.start + end - dest.len())
+                else {
+                    continue;
+                };
+                let span = start..start + dest.len();
+                references.push(Reference {
+                    image,
+                    wiki: false,
+                    dest,
+                    label: None,
+                    span,
+                });
+            }
+            _ => {}
+        }
+    }
+    references
+}
+
+/// Replaces each span of `markdown` with its text. Spans overlapping an
+/// earlier one are ignored.
+pub fn replace_spans(markdown: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    replacements.sort_by_key(|(span, _)| span.start);
+    let mut out = String::with_capacity(markdown.len());
+    let mut copied = 0;
+    for (span, text) in replacements {
+        if span.start < copied {
+            continue;
+        }
+        out.push_str(&markdown[copied..span.start]);
+        out.push_str(&text);
+        copied = span.end;
+    }
+    out.push_str(&markdown[copied..]);
+    out
+}
+
+/// Replaces the source of each inline image `rewrite` returns a new one for,
+/// leaving the rest of the Markdown as written.
+pub fn rewrite_images(markdown: &str, mut rewrite: impl FnMut(&str) -> Option<String>) -> String {
+    let replacements = references(markdown)
+        .into_iter()
+        .filter(|reference| reference.image && !reference.wiki)
+        .filter_map(|reference| Some((reference.span, rewrite(&reference.dest)?)))
+        .collect();
+    replace_spans(markdown, replacements)
+}
+
+/// How a photo name is written as an image source.
+pub fn photo_src(name: &str) -> String {
+    utf8_percent_encode(name, SRC).to_string()
+}
+
+/// Words of prose and code, leaving out Markdown syntax, link targets and
+/// HTML tags.
+pub fn word_count(markdown: &str) -> usize {
+    Parser::new_ext(markdown, options())
+        .map(|event| match event {
+            Event::Text(text) | Event::Code(text) => text.split_whitespace().count(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// A heading in a note.
+pub struct Heading {
+    /// 1 for `#` through 6 for `######`.
+    pub level: u8,
+    /// Its text without Markdown syntax.
+    pub text: String,
+    /// An anchor for the heading, unique within the note.
+    pub slug: String,
+    /// Where the heading is written, markers included.
+    pub span: Range<usize>,
+}
+
+/// GitHub style anchors: lower case, spaces as dashes and other punctuation
+/// dropped.
+fn slugify(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' | '-' => Some('-'),
+            '_' => Some('_'),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The headings in `markdown`, in order.
+pub fn headings(markdown: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut current: Option<Heading> = None;
+    let mut slugs = HashMap::new();
+    for (event, range) in Parser::new_ext(markdown, options()).into_offset_iter() {
+        match (event, &mut current) {
+            (Event::Start(Tag::Heading { level, .. }), None) => {
+                current = Some(Heading {
+                    level: level as u8,
+                    text: String::new(),
+                    slug: String::new(),
+                    span: range,
+                });
+            }
+            (Event::Text(text) | Event::Code(text), Some(heading)) => heading.text.push_str(&text),
+            (Event::SoftBreak | Event::HardBreak, Some(heading)) => heading.text.push(' '),
+            (Event::End(TagEnd::Heading(_)), Some(_)) => {
+                let mut heading = current.take().unwrap();
+                heading.text = heading.text.trim().to_owned();
+                // Repeated headings get -1, -2 and so on, as on GitHub.
+                let slug = slugify(&heading.text);
+                let seen = slugs.entry(slug.clone()).or_insert(0);
+                heading.slug = match *seen {
+                    0 => slug,
+                    n => format!("{}-{}", slug, n),
+                };
+                *seen += 1;
+                headings.push(heading);
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+fn highlight(code: &str, lang: &str) -> String {
+    let syntax = SYNTAXES
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    highlighted_html_for_string(code, &SYNTAXES, syntax, &THEMES.themes[THEME])
+        .unwrap_or_else(|_| format!("<pre><code>{}</code></pre>", escape(code)))
+}
+
+/// Replaces fenced code blocks that name a language with highlighted HTML.
+fn highlight_code<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut out = Vec::new();
+    let mut block: Option<(CowStr, String)> = None;
+    for event in events {
+        match (event, &mut block) {
+            (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))), None)
+                if !lang.is_empty() =>
+            {
+                block = Some((lang, String::new()));
+            }
+            (Event::Text(text), Some((_, code))) => code.push_str(&text),
+            (Event::End(TagEnd::CodeBlock), Some((lang, code))) => {
+                out.push(Event::Html(highlight(code, lang).into()));
+                block = None;
+            }
+            (event, _) => out.push(event),
+        }
+    }
+    out
+}
+
+/// Syntax highlighting is the only thing allowed to style elements, so only
+/// the declarations it produces make it through.
+fn is_highlight_style(style: &str) -> bool {
+    style
+        .split(';')
+        .map(str::trim)
+        .filter(|declaration| !declaration.is_empty())
+        .all(|declaration| match declaration.split_once(':') {
+            Some(("color" | "background-color", value)) => {
+                let value = value.trim();
+                value.len() <= 9
+                    && value
+                        .strip_prefix('#')
+                        .is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+            }
+            Some(("font-weight", value)) => value.trim() == "bold",
+            Some(("font-style", value)) => value.trim() == "italic",
+            Some(("text-decoration", value)) => value.trim() == "underline",
+            _ => false,
+        })
+}
+
+/// Renders Markdown with GFM tables, task lists, footnotes and highlighted
+/// code to HTML that is safe to serve as is. Image sources naming a photo in
+/// `photos` are replaced with its URL there.
+pub fn markdown_to_html(markdown: &str, photos: &HashMap<String, String>) -> String {
+    let events = highlight_code(Parser::new_ext(markdown, options()));
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, events.into_iter());
+
+    let photos = photos.clone();
+    Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["checked", "disabled"])
+        .add_tag_attribute_values("input", "type", ["checkbox"])
+        .add_tag_attributes("pre", ["style"])
+        .add_tag_attributes("span", ["style"])
+        .add_tag_attributes("div", ["id"])
+        .add_allowed_classes("sup", ["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", ["footnote-definition"])
+        .attribute_filter(
+            move |element, attribute, value| match (element, attribute) {
+                (_, "style") if !is_highlight_style(value) => None,
+                ("img", "src") => match photo_name(value).and_then(|name| photos.get(&name)) {
+                    Some(url) => Some(Cow::Owned(url.clone())),
+                    None => Some(Cow::Borrowed(value)),
+                },
+                _ => Some(Cow::Borrowed(value)),
+            },
+        )
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wraps already sanitized `body` in a standalone HTML document.
+pub fn page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{}</title>
+<style>{}</style>
+</head>
+<body>
+<main>
+{}
+</main>
+</body>
+</html>
+"#,
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        headings, markdown_to_html, page, photo_refs, references, rewrite_images, word_count,
+    };
+
+    fn render(markdown: &str) -> String {
+        markdown_to_html(markdown, &HashMap::new())
+    }
+
+    #[test]
+    fn renders_commonmark() {
+        let html = render("# Title\n\nSome *emphasis* and [a link](https://example.com).");
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<em>emphasis</em>"));
+        assert!(html.contains(r#"href="https://example.com""#));
+    }
+
+    #[test]
+    fn renders_gfm() {
+        let html = render(
+            "| a | b |\n|---|---|\n| 1 | 2 |\n\n- [x] done\n- [ ] todo\n\nSee[^1].\n\n[^1]: The note.",
+        );
+        assert!(html.contains("<td>1</td>"));
+        assert!(html.contains(r#"type="checkbox""#));
+        assert!(html.contains("checked"));
+        assert!(html.contains(r#"class="footnote-reference""#));
+        assert!(html.contains(r#"class="footnote-definition""#));
+    }
+
+    #[test]
+    fn highlights_code() {
+        let html = render("```rust\nfn main() {}\n```");
+        assert!(html.contains("<pre style=\"background-color:#"));
+        assert!(html.contains("<span style=\"color:#"));
+        assert!(html.contains("main"));
+    }
+
+    #[test]
+    fn strips_scripts_and_styles() {
+        let html = render(
+            "<script>alert(1)</script>\n\n<a href=\"javascript:alert(1)\" onclick=\"x()\">hi</a>\n\n<span style=\"position:fixed\">x</span>",
+        );
+        assert!(!html.contains("script"));
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("position"));
+    }
+
+    #[test]
+    fn resolves_photos() {
+        let markdown = "![cat](cat%20one.png) ![dog](/photos/dog.png) ![web](https://example.com/x.png) ![](cat%20one.png)";
+        assert_eq!(photo_refs(markdown), ["cat one.png", "dog.png"]);
+        assert_eq!(
+            photo_refs("![[fox.png]]\n\n<img alt=\"owl\" SRC='/photos/owl.png'>\n\nA <img src=bee.png/> here"),
+            ["fox.png", "owl.png", "bee.png"]
+        );
+
+        let photos = HashMap::from([(
+            "cat one.png".to_owned(),
+            "data:image/png;base64,AAAA".to_owned(),
+        )]);
+        let html = markdown_to_html(markdown, &photos);
+        assert!(html.contains(r#"src="data:image/png;base64,AAAA""#));
+        assert!(html.contains(r#"src="/photos/dog.png""#));
+        assert!(html.contains(r#"src="https://example.com/x.png""#));
+    }
+
+    #[test]
+    fn rewrites_image_sources() {
+        let markdown = "![cat](cat.png \"cat.png\") and ![dog](<dog.png>) but not [cat](cat.png)\n\n![ref][r]\n\n[r]: cat.png";
+        let rewritten = rewrite_images(markdown, |src| {
+            (src != "dog.png").then(|| format!("photos/{}", src))
+        });
+        assert_eq!(
+            rewritten,
+            "![cat](photos/cat.png \"cat.png\") and ![dog](<dog.png>) but not [cat](cat.png)\n\n![ref][r]\n\n[r]: cat.png"
+        );
+    }
+
+    #[test]
+    fn finds_wiki_links() {
+        let markdown = "See [[Other note|the other]] and ![[cat.png]], not `[[code]]`.";
+        let found = references(markdown);
+        assert_eq!(found.len(), 2);
+        assert!(found[0].wiki && !found[0].image);
+        assert_eq!(&*found[0].dest, "Other note");
+        assert_eq!(found[0].label, Some("the other"));
+        assert_eq!(&markdown[found[0].span.clone()], "[[Other note|the other]]");
+        assert!(found[1].wiki && found[1].image);
+        assert_eq!(&markdown[found[1].span.clone()], "![[cat.png]]");
+    }
+
+    #[test]
+    fn counts_words() {
+        assert_eq!(
+            word_count("# Two words\n\n[a link](https://example.com/x) and `code`\n\n- [ ] task"),
+            7
+        );
+        assert_eq!(word_count(""), 0);
+    }
+
+    #[test]
+    fn finds_headings() {
+        let markdown =
+            "# Plan\n\nText\n\n## The `main` goal\n\n```\n# not a heading\n```\n\nSetext\n---\n";
+        let found: Vec<_> = headings(markdown)
+            .into_iter()
+            .map(|heading| {
+                (
+                    heading.level,
+                    heading.text,
+                    heading.slug,
+                    heading.span.start,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "Plan".to_owned(), "plan".to_owned(), 0),
+                (
+                    2,
+                    "The main goal".to_owned(),
+                    "the-main-goal".to_owned(),
+                    14
+                ),
+                (2, "Setext".to_owned(), "setext".to_owned(), 59)
+            ]
+        );
+        let slugs: Vec<_> = headings("# Q&A: Café\n# Q&A: Café\n# Q&A: Café")
+            .into_iter()
+            .map(|heading| heading.slug)
+            .collect();
+        assert_eq!(slugs, ["qa-café", "qa-café-1", "qa-café-2"]);
+    }
+
+    #[test]
+    fn escapes_titles() {
+        assert!(page("<b>Plan</b>", "").contains("<title>&lt;b&gt;Plan&lt;/b&gt;</title>"));
+    }
+}
@@ -0,0 +1,519 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgPool};
+use strum_macros::{Display, EnumString};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    note::Note,
+    AppState,
+};
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ShareRole {
+    Viewer,
+    Editor,
+}
+
+/// What a user may do with a note, from least to most.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum Access {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl From<ShareRole> for Access {
+    fn from(role: ShareRole) -> Self {
+        match role {
+            ShareRole::Viewer => Access::Viewer,
+            ShareRole::Editor => Access::Editor,
+        }
+    }
+}
+
+fn not_found() -> AppError {
+    AppError::WithStatus(StatusCode::NOT_FOUND, anyhow::Error::msg("No such note"))
+}
+
+/// Checks that `user_id` has at least `needed` access to a note. Notes the
+/// user can't see at all are reported as missing rather than forbidden.
+pub async fn require(
+    db: &PgPool,
+    note_id: Uuid,
+    user_id: Uuid,
+    needed: Access,
+) -> Result<Access, AppError> {
+    let row = query!(
+        r#"select note.author_id, note_share.role as "role?"
+        from note
+        left join note_share on note_share.note_id = note.id and note_share.user_id = $2
+        where note.id = $1"#,
+        note_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(not_found)?;
+    let access = if row.author_id == user_id {
+        Access::Owner
+    } else {
+        row.role
+            .and_then(|role| ShareRole::from_str(&role).ok())
+            .map(Access::from)
+            .ok_or_else(not_found)?
+    };
+    if access < needed {
+        return Err(AppError::WithStatus(
+            StatusCode::FORBIDDEN,
+            anyhow::Error::msg(format!("{:?} access is required", needed)),
+        ));
+    }
+    Ok(access)
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NoteShare {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub role: ShareRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct NewNoteShare {
+    /// Email of the user to share with when it contains an `@`, and their
+    /// username otherwise.
+    user: String,
+    role: ShareRole,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SharedNote {
+    pub note: Note,
+    pub role: ShareRole,
+}
+
+struct NoteShareRow {
+    user_id: Uuid,
+    username: String,
+    email: String,
+    role: String,
+    created_at: DateTime<Utc>,
+}
+
+impl TryFrom<NoteShareRow> for NoteShare {
+    type Error = AppError;
+
+    fn try_from(row: NoteShareRow) -> Result<Self, AppError> {
+        Ok(NoteShare {
+            user_id: row.user_id,
+            username: row.username,
+            email: row.email,
+            role: ShareRole::from_str(&row.role)?,
+            created_at: row.created_at,
+        })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/shares",
+    tag = "sharing",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, body = Vec<NoteShare>)),
+    security(("clerk" = []))
+)]
+pub async fn get_all(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<NoteShare>> {
+    require(&app.db, id, user.id, Access::Owner).await?;
+    let shares = query_as!(
+        NoteShareRow,
+        "select note_share.user_id, users.username, users.email, note_share.role, note_share.created_at
+        from note_share join users on users.id = note_share.user_id
+        where note_share.note_id = $1
+        order by note_share.created_at",
+        id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let shares = shares
+        .into_iter()
+        .map(NoteShare::try_from)
+        .collect::<Result<_, _>>()?;
+    Ok(Json(shares))
+}
+
+#[utoipa::path(
+    post,
+    path = "/note/{id}/shares",
+    tag = "sharing",
+    params(("id" = Uuid, Path, description = "Note id")),
+    request_body = NewNoteShare,
+    responses(
+        (status = 200, description = "The share, updated if it already existed", body = NoteShare),
+        (status = 404, description = "No such note or user"),
+        (status = 409, description = "More than one user has the email address")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn create(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<NewNoteShare>,
+) -> JsonRes<NoteShare> {
+    require(&app.db, id, user.id, Access::Owner).await?;
+    // Emails aren't unique, so more than one user may have the address.
+    let mut targets = query!(
+        "select id, username, email from users
+        where case when $2 then lower(email) = lower($1) else username = $1 end
+        limit 2",
+        doc.user,
+        doc.user.contains('@')
+    )
+    .fetch_all(&app.db)
+    .await?;
+    if targets.len() > 1 {
+        return Err(AppError::WithStatus(
+            StatusCode::CONFLICT,
+            anyhow::Error::msg("Ambiguous recipient, share by username instead"),
+        ));
+    }
+    let target = targets.pop().ok_or_else(|| {
+        AppError::WithStatus(StatusCode::NOT_FOUND, anyhow::Error::msg("No such user"))
+    })?;
+    if target.id == user.id {
+        return Err(AppError::WithStatus(
+            StatusCode::BAD_REQUEST,
+            anyhow::Error::msg("You already own this note"),
+        ));
+    }
+    let created_at = query!(
+        "insert into note_share (note_id, user_id, role) values ($1, $2, $3)
+        on conflict (note_id, user_id) do update set role = excluded.role
+        returning created_at",
+        id,
+        target.id,
+        doc.role.to_string()
+    )
+    .fetch_one(&app.db)
+    .await?
+    .created_at;
+    Ok(Json(NoteShare {
+        user_id: target.id,
+        username: target.username,
+        email: target.email,
+        role: doc.role,
+        created_at,
+    }))
+}
+
+/// Owners can remove anyone, and anyone can remove themselves.
+#[utoipa::path(
+    delete,
+    path = "/note/{id}/shares/{user_id}",
+    tag = "sharing",
+    params(
+        ("id" = Uuid, Path, description = "Note id"),
+        ("user_id" = Uuid, Path, description = "User the note is shared with")
+    ),
+    responses((status = 200, description = "The removed share", body = NoteShare)),
+    security(("clerk" = []))
+)]
+pub async fn delete(
+    Path((id, user_id)): Path<(Uuid, Uuid)>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<NoteShare> {
+    let needed = if user_id == user.id {
+        Access::Viewer
+    } else {
+        Access::Owner
+    };
+    require(&app.db, id, user.id, needed).await?;
+    let share = query_as!(
+        NoteShareRow,
+        "with share as (
+            delete from note_share where note_id = $1 and user_id = $2
+            returning user_id, role, created_at
+        )
+        select share.user_id, users.username, users.email, share.role, share.created_at
+        from share join users on users.id = share.user_id",
+        id,
+        user_id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(|| {
+        AppError::WithStatus(
+            StatusCode::NOT_FOUND,
+            anyhow::Error::msg("Note isn't shared with this user"),
+        )
+    })?;
+    Ok(Json(share.try_into()?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notes/shared",
+    tag = "sharing",
+    responses((status = 200, description = "Notes other users have shared with you", body = Vec<SharedNote>)),
+    security(("clerk" = []))
+)]
+pub async fn shared_with_me(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<SharedNote>> {
+    let rows = query!(
+        "select note.*, note_share.role
+        from note join note_share on note_share.note_id = note.id
+        where note_share.user_id = $1
+        order by note.updated_at desc",
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    let notes = rows
+        .into_iter()
+        .map(|row| {
+            Ok(SharedNote {
+                note: Note {
+                    id: row.id,
+                    author_id: row.author_id,
+                    title: row.title,
+                    content: row.content,
+                    tags: row.tags,
+                    pinned: row.pinned,
+                    favorite: row.favorite,
+                    archived: row.archived,
+                    word_count: row.word_count,
+                    char_count: row.char_count,
+                    reading_minutes: row.reading_minutes,
+                    outline: row.outline,
+                    version: row.version,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                },
+                role: ShareRole::from_str(&row.role)?,
+            })
+        })
+        .collect::<Result<_, AppError>>()?;
+    Ok(Json(notes))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use sqlx::query;
+    use uuid::Uuid;
+
+    use super::{NoteShare, ShareRole, SharedNote};
+    use crate::{
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    async fn shared_note(app: &TestApp, role: &str) -> (Note, Uuid) {
+        app.create_user("user_a").await;
+        let guest = app.create_user("user_b").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Plan", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+        let res = app
+            .post_json(
+                &format!("/note/{}/shares", note.id),
+                "user_a",
+                json!({ "user": "user_b@example.com", "role": role }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let share: NoteShare = json(res).await;
+        assert_eq!(share.user_id, guest);
+        (note, guest)
+    }
+
+    #[tokio::test]
+    async fn viewers_can_only_read() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let (note, _) = shared_note(&app, "viewer").await;
+        let uri = format!("/note/{}", note.id);
+
+        assert_eq!(app.get(&uri, "user_b").await.status(), StatusCode::OK);
+        let res = app
+            .post_json(&uri, "user_b", json!({ "content": "Mine now" }))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            app.delete(&uri, "user_b").await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let shared: Vec<SharedNote> = json(app.get("/notes/shared", "user_b").await).await;
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].note.id, note.id);
+        assert_eq!(shared[0].role, ShareRole::Viewer);
+    }
+
+    #[tokio::test]
+    async fn finds_recipients_by_email_or_username() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let guest = app.create_user("user_b").await;
+        // Someone whose username is user_b's email.
+        app.create_user("user_b@example.com").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Plan", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+        let uri = format!("/note/{}/shares", note.id);
+        for name in ["user_b@example.com", "user_b"] {
+            let share: NoteShare = json(
+                app.post_json(&uri, "user_a", json!({ "user": name, "role": "viewer" }))
+                    .await,
+            )
+            .await;
+            assert_eq!(share.user_id, guest);
+        }
+        let res = app
+            .post_json(
+                &uri,
+                "user_a",
+                json!({ "user": "USER_B@Example.com", "role": "viewer" }),
+            )
+            .await;
+        assert_eq!(json::<NoteShare>(res).await.user_id, guest);
+
+        let twin = app.create_user("user_c").await;
+        query!(
+            "update users set email = 'user_b@example.com' where id = $1",
+            twin
+        )
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+        let res = app
+            .post_json(
+                &uri,
+                "user_a",
+                json!({ "user": "user_b@example.com", "role": "viewer" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn editors_can_update_but_not_delete() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let (note, _) = shared_note(&app, "editor").await;
+        let uri = format!("/note/{}", note.id);
+
+        let res = app
+            .post_json(&uri, "user_b", json!({ "content": "Edited" }))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            app.delete(&uri, "user_b").await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let res = app
+            .post_json(
+                &format!("/note/{}/shares", note.id),
+                "user_b",
+                json!({ "user": "user_c", "role": "editor" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unsharing_revokes_access() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        let (note, guest) = shared_note(&app, "viewer").await;
+        let shares: Vec<NoteShare> = json(
+            app.get(&format!("/note/{}/shares", note.id), "user_a")
+                .await,
+        )
+        .await;
+        assert_eq!(shares.len(), 1);
+
+        let res = app
+            .delete(&format!("/note/{}/shares/{}", note.id, guest), "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.get(&format!("/note/{}", note.id), "user_b").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let shared: Vec<SharedNote> = json(app.get("/notes/shared", "user_b").await).await;
+        assert!(shared.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_rejected() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Plan", "content": "" }),
+            )
+            .await,
+        )
+        .await;
+        let res = app
+            .post_json(
+                &format!("/note/{}/shares", note.id),
+                "user_a",
+                json!({ "user": "nobody", "role": "viewer" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,303 @@
+//! Counts kept with every note, and how many words each user writes a day.
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgPool};
+use ts_rs::TS;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    render::{headings, word_count},
+    AppState,
+};
+
+const WORDS_PER_MINUTE: usize = 200;
+/// How far back stats go when no start is given.
+const DEFAULT_DAYS: u64 = 30;
+
+/// What is stored alongside a note's content, worked out again whenever the
+/// content changes.
+pub struct NoteStats {
+    pub word_count: i32,
+    pub char_count: i32,
+    pub reading_minutes: i32,
+    /// The headings as Markdown without inline syntax, e.g. `## Goals`.
+    pub outline: Vec<String>,
+}
+
+impl NoteStats {
+    pub fn of(content: &str) -> Self {
+        let words = word_count(content);
+        NoteStats {
+            word_count: words as i32,
+            char_count: content.chars().count() as i32,
+            reading_minutes: words.div_ceil(WORDS_PER_MINUTE) as i32,
+            outline: headings(content)
+                .into_iter()
+                .map(|heading| format!("{} {}", "#".repeat(heading.level.into()), heading.text))
+                .collect(),
+        }
+    }
+}
+
+/// Credits `user_id` with the words a save added today. Removing words
+/// doesn't take any away.
+pub async fn record(db: &PgPool, user_id: Uuid, before: i32, after: i32) -> Result<(), AppError> {
+    if after <= before {
+        return Ok(());
+    }
+    query!(
+        "insert into writing_day (user_id, date, words)
+        values ($1, (now() at time zone 'utc')::date, $2)
+        on conflict (user_id, date) do update set words = writing_day.words + excluded.words",
+        user_id,
+        after - before
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Works out the stats of every note again, for notes saved before they
+/// were kept.
+pub async fn recount_all(db: &PgPool) -> anyhow::Result<u64> {
+    let notes = query!("select id, content from note").fetch_all(db).await?;
+    let mut updated = 0;
+    for note in notes {
+        let stats = NoteStats::of(&note.content);
+        updated += query!(
+            "update note set word_count = $2, char_count = $3, reading_minutes = $4, outline = $5
+            where id = $1 and (word_count, char_count, reading_minutes, outline) <> ($2, $3, $4, $5)",
+            note.id,
+            stats.word_count,
+            stats.char_count,
+            stats.reading_minutes,
+            &stats.outline
+        )
+        .execute(db)
+        .await?
+        .rows_affected();
+    }
+    Ok(updated)
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsPeriod {
+    #[default]
+    Day,
+    Week,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct StatsQuery {
+    /// Totals per day, or per week starting on Monday.
+    #[serde(default)]
+    period: StatsPeriod,
+    /// The first day to count, 30 days ago when left out.
+    since: Option<NaiveDate>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WritingPeriod {
+    /// The day, or the Monday the week starts on.
+    pub start: NaiveDate,
+    pub words: i64,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WritingStats {
+    pub notes: i64,
+    /// Words across all of your notes now.
+    pub words: i64,
+    pub reading_minutes: i64,
+    /// Words written in each period, leaving out periods without any.
+    pub periods: Vec<WritingPeriod>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/notes/stats",
+    tag = "notes",
+    params(StatsQuery),
+    responses((status = 200, body = WritingStats)),
+    security(("clerk" = []))
+)]
+pub async fn get(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<StatsQuery>,
+) -> JsonRes<WritingStats> {
+    let since = query
+        .since
+        .unwrap_or_else(|| Utc::now().date_naive() - Days::new(DEFAULT_DAYS));
+    let totals = query!(
+        r#"select count(*) as "notes!", coalesce(sum(word_count), 0) as "words!",
+            coalesce(sum(reading_minutes), 0) as "reading_minutes!"
+        from note where author_id = $1"#,
+        user.id
+    )
+    .fetch_one(&app.db)
+    .await?;
+    let periods = match query.period {
+        StatsPeriod::Day => {
+            query_as!(
+                WritingPeriod,
+                r#"select date as start, words::bigint as "words!" from writing_day
+                where user_id = $1 and date >= $2 and words > 0
+                order by date"#,
+                user.id,
+                since
+            )
+            .fetch_all(&app.db)
+            .await?
+        }
+        StatsPeriod::Week => {
+            query_as!(
+                WritingPeriod,
+                r#"select date_trunc('week', date)::date as "start!", sum(words) as "words!"
+                from writing_day
+                where user_id = $1 and date >= $2 and words > 0
+                group by 1 order by 1"#,
+                user.id,
+                since
+            )
+            .fetch_all(&app.db)
+            .await?
+        }
+    };
+    Ok(Json(WritingStats {
+        notes: totals.notes,
+        words: totals.words,
+        reading_minutes: totals.reading_minutes,
+        periods,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::{NoteStats, WritingStats};
+    use crate::{
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    #[test]
+    fn counts_content() {
+        let stats = NoteStats::of("# Plan\n\nGo *outside* today\n\n## Café");
+        assert_eq!(stats.word_count, 5);
+        assert_eq!(stats.char_count, 35);
+        assert_eq!(stats.reading_minutes, 1);
+        assert_eq!(stats.outline, ["# Plan", "## Café"]);
+        assert_eq!(NoteStats::of("").reading_minutes, 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_stats_and_totals() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let note: Note = json(
+            app.post_json(
+                "/notes",
+                "user_a",
+                json!({ "title": "Plan", "content": "# Plan\n\nOne two" }),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(note.word_count, 3);
+        assert_eq!(note.outline, ["# Plan"]);
+
+        let res = app
+            .post_json(
+                &format!("/note/{}", note.id),
+                "user_a",
+                json!({ "content": "# Plan\n\nOne two three four\n\n## Next" }),
+            )
+            .await;
+        let note: Note = json(res).await;
+        assert_eq!(note.word_count, 6);
+        assert_eq!(note.outline, ["# Plan", "## Next"]);
+        // Deleting words doesn't count against the day.
+        app.post_json(
+            &format!("/note/{}", note.id),
+            "user_a",
+            json!({ "content": "Gone" }),
+        )
+        .await;
+
+        let res = app.get("/notes/stats", "user_a").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let stats: WritingStats = json(res).await;
+        assert_eq!(stats.notes, 1);
+        assert_eq!(stats.words, 1);
+        assert_eq!(stats.periods.len(), 1);
+        assert_eq!(stats.periods[0].start, Utc::now().date_naive());
+        assert_eq!(stats.periods[0].words, 6);
+
+        let stats: WritingStats = json(app.get("/notes/stats?period=week", "user_a").await).await;
+        assert_eq!(stats.periods[0].words, 6);
+        let stats: WritingStats =
+            json(app.get("/notes/stats?since=2999-01-01", "user_a").await).await;
+        assert!(stats.periods.is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaves_template_text_uncounted() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let template: serde_json::Value = json(
+            app.post_json(
+                "/templates",
+                "user_a",
+                json!({ "name": "Standup", "title": "Standup", "content": "Done doing blocked" }),
+            )
+            .await,
+        )
+        .await;
+        let res = app
+            .post_json(
+                &format!("/notes/from-template/{}", template["id"].as_str().unwrap()),
+                "user_a",
+                json!({}),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let note: Note = json(res).await;
+
+        let stats: WritingStats = json(app.get("/notes/stats", "user_a").await).await;
+        assert!(stats.periods.is_empty());
+
+        // Words added to the note afterwards are the author's.
+        let res = app
+            .patch_json(
+                &format!("/note/{}", note.id),
+                "user_a",
+                json!({ "version": 1, "edits": [{ "start": 18, "end": 18, "text": " nothing" }] }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let stats: WritingStats = json(app.get("/notes/stats", "user_a").await).await;
+        assert_eq!(stats.periods.len(), 1);
+        assert_eq!(stats.periods[0].words, 1);
+    }
+}
@@ -0,0 +1,112 @@
+#[cfg(test)]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use aws_sdk_s3::primitives::ByteStream;
+use axum::body::Bytes;
+
+use crate::telemetry::time_s3;
+
+#[derive(Clone)]
+pub struct Object {
+    pub bytes: Bytes,
+    pub content_type: Option<String>,
+}
+
+/// The bucket photos live in. Tests swap R2 for an in-memory map.
+#[derive(Clone)]
+pub enum Storage {
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+    },
+    #[cfg(test)]
+    Memory(Arc<Mutex<HashMap<String, Object>>>),
+}
+
+impl Storage {
+    pub async fn put(&self, key: &str, bytes: Bytes, content_type: Option<&str>) -> Result<()> {
+        match self {
+            Storage::S3 { client, bucket } => {
+                time_s3(
+                    "put_object",
+                    client
+                        .put_object()
+                        .bucket(bucket)
+                        .key(key)
+                        .set_content_type(content_type.map(|s| s.to_owned()))
+                        .body(ByteStream::from(bytes))
+                        .send(),
+                )
+                .await?;
+            }
+            #[cfg(test)]
+            Storage::Memory(objects) => {
+                objects.lock().unwrap().insert(
+                    key.to_owned(),
+                    Object {
+                        bytes,
+                        content_type: content_type.map(|s| s.to_owned()),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Object> {
+        match self {
+            Storage::S3 { client, bucket } => {
+                let object = time_s3(
+                    "get_object",
+                    client.get_object().bucket(bucket).key(key).send(),
+                )
+                .await?;
+                let bytes = object.body.collect().await?.into_bytes();
+                Ok(Object {
+                    bytes,
+                    content_type: object.content_type,
+                })
+            }
+            #[cfg(test)]
+            Storage::Memory(objects) => objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| anyhow::Error::msg(format!("No such key: {}", key))),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Storage::S3 { client, bucket } => {
+                time_s3(
+                    "delete_object",
+                    client.delete_object().bucket(bucket).key(key).send(),
+                )
+                .await?;
+            }
+            #[cfg(test)]
+            Storage::Memory(objects) => {
+                objects.lock().unwrap().remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the bucket exists and the credentials can reach it.
+    pub async fn check(&self) -> Result<()> {
+        match self {
+            Storage::S3 { client, bucket } => {
+                time_s3("head_bucket", client.head_bucket().bucket(bucket).send()).await?;
+            }
+            #[cfg(test)]
+            Storage::Memory(_) => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::{future::Future, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::{error::AppError, AppState};
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_owned()),
+            LATENCY_BUCKETS,
+        )?
+        .install_recorder()?;
+    Ok(handle)
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses(
+        (status = 200, description = "Prometheus text exposition", body = String),
+        (status = 401, description = "Missing or wrong `Bearer` metrics token"),
+        (status = 404, description = "No metrics token is configured")
+    )
+)]
+pub async fn get(State(app): State<AppState>, headers: HeaderMap) -> Result<String, AppError> {
+    let token = &app.config.metrics_token;
+    if token.is_empty() {
+        return Err(AppError::WithStatus(
+            StatusCode::NOT_FOUND,
+            anyhow::Error::msg("Metrics are disabled"),
+        ));
+    }
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given != Some(token.as_str()) {
+        return Err(AppError::WithStatus(
+            StatusCode::UNAUTHORIZED,
+            anyhow::Error::msg("Invalid metrics token"),
+        ));
+    }
+    gauge!("db_pool_connections").set(app.db.size() as f64);
+    gauge!("db_pool_idle_connections").set(app.db.num_idle() as f64);
+    gauge!("db_pool_max_connections").set(app.config.db_max_connections as f64);
+    Ok(app.metrics.render())
+}
+
+/// Records request counts and latencies per route. Requests that didn't match
+/// a route share one label so unknown paths can't blow up cardinality.
+pub async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+    counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "path" => path.clone(),
+        "status" => status
+    )
+    .increment(1);
+    histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "path" => path
+    )
+    .record(start.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn time_s3<T, E>(
+    operation: &'static str,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let result = fut.await;
+    let status = if result.is_ok() { "ok" } else { "error" };
+    histogram!(
+        "s3_operation_duration_seconds",
+        "operation" => operation,
+        "status" => status
+    )
+    .record(start.elapsed().as_secs_f64());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::AUTHORIZATION, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::{build_app, testing::offline_state};
+
+    async fn status(token: Option<&str>) -> StatusCode {
+        let mut request = Request::get("/metrics");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let app = build_app(offline_state());
+        let res = app.oneshot(request.body(Body::empty()).unwrap()).await;
+        res.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn requires_the_metrics_token() {
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("metrics")).await, StatusCode::OK);
+    }
+}
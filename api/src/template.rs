@@ -0,0 +1,401 @@
+//! Reusable skeletons for new notes. `{{placeholders}}` in a template's title
+//! and content are filled in when a note is made from it.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::query_as;
+use ts_rs::TS;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    clerk::User,
+    error::{AppError, JsonRes},
+    note::{self, Note},
+    weather::{self, TemperatureUnit, WeatherQuery},
+    AppState,
+};
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NoteTemplate {
+    id: Uuid,
+    author_id: Uuid,
+    name: String,
+    title: String,
+    content: String,
+    tags: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct NewNoteTemplate {
+    name: String,
+    title: String,
+    content: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateNoteTemplate {
+    name: Option<String>,
+    title: Option<String>,
+    content: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct FromTemplateQuery {
+    /// Where `{{weather}}` is looked up. It's left empty without a location.
+    lat: Option<f64>,
+    lon: Option<f64>,
+    unit: Option<TemperatureUnit>,
+}
+
+fn not_found() -> AppError {
+    AppError::WithStatus(
+        StatusCode::NOT_FOUND,
+        anyhow::Error::msg("No such template"),
+    )
+}
+
+/// Splits `text` around its `{{key}}` placeholders, calling `placeholder`
+/// with each trimmed key and its position.
+fn scan<'a>(text: &'a str, mut placeholder: impl FnMut(&'a str, usize, usize)) {
+    let mut from = 0;
+    while let Some(start) = text[from..].find("{{").map(|i| from + i) {
+        let Some(end) = text[start..].find("}}").map(|i| start + i + 2) else {
+            break;
+        };
+        placeholder(text[start + 2..end - 2].trim(), start, end);
+        from = end;
+    }
+}
+
+/// Replaces the placeholders there are values for, leaving the rest as they
+/// are.
+fn fill(text: &str, values: &HashMap<&str, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    scan(text, |key, start, end| {
+        if let Some(value) = values.get(key) {
+            out.push_str(&text[copied..start]);
+            out.push_str(value);
+            copied = end;
+        }
+    });
+    out.push_str(&text[copied..]);
+    out
+}
+
+fn uses(text: &str, key: &str) -> bool {
+    let mut used = false;
+    scan(text, |found, _, _| used |= found == key);
+    used
+}
+
+async fn weather_text(app: &AppState, query: &FromTemplateQuery) -> String {
+    let (Some(lat), Some(lon)) = (query.lat, query.lon) else {
+        return String::new();
+    };
+    let unit = query.unit.unwrap_or(TemperatureUnit::C);
+    let weather = weather::current(app, &WeatherQuery { lat, lon, unit }).await;
+    match weather {
+        Ok(weather) => weather.summary(unit),
+        Err(AppError::WithStatus(_, error) | AppError::Internal(error)) => {
+            tracing::warn!("Couldn't fill in the weather: {}", error);
+            String::new()
+        }
+    }
+}
+
+/// The values placeholders can take, e.g. `{{date}}` or `{{user.first_name}}`.
+async fn values(
+    app: &AppState,
+    user: &User,
+    query: &FromTemplateQuery,
+    text: &str,
+) -> HashMap<&'static str, String> {
+    let now = Utc::now();
+    let mut values = HashMap::from([
+        ("date", now.format("%Y-%m-%d").to_string()),
+        ("time", now.format("%H:%M").to_string()),
+        ("user.first_name", user.first_name.clone()),
+        ("user.last_name", user.last_name.clone()),
+        ("user.username", user.username.clone()),
+    ]);
+    if uses(text, "weather") {
+        values.insert("weather", weather_text(app, query).await);
+    }
+    values
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates",
+    tag = "templates",
+    responses((status = 200, body = Vec<NoteTemplate>)),
+    security(("clerk" = []))
+)]
+pub async fn get_all(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<NoteTemplate>> {
+    let templates = query_as!(
+        NoteTemplate,
+        "select * from note_template where author_id = $1 order by name",
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(templates))
+}
+
+#[utoipa::path(
+    post,
+    path = "/templates",
+    tag = "templates",
+    request_body = NewNoteTemplate,
+    responses((status = 200, body = NoteTemplate)),
+    security(("clerk" = []))
+)]
+pub async fn create(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<NewNoteTemplate>,
+) -> JsonRes<NoteTemplate> {
+    let template = query_as!(
+        NoteTemplate,
+        "insert into note_template (author_id, name, title, content, tags)
+        values ($1, $2, $3, $4, $5) returning *",
+        user.id,
+        doc.name,
+        doc.title,
+        doc.content,
+        &doc.tags
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(Json(template))
+}
+
+#[utoipa::path(
+    post,
+    path = "/template/{id}",
+    tag = "templates",
+    params(("id" = Uuid, Path, description = "Template id")),
+    request_body = UpdateNoteTemplate,
+    responses(
+        (status = 200, body = NoteTemplate),
+        (status = 404, description = "No such template")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn update(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<UpdateNoteTemplate>,
+) -> JsonRes<NoteTemplate> {
+    let template = query_as!(
+        NoteTemplate,
+        "update note_template set
+            name = coalesce($3, name),
+            title = coalesce($4, title),
+            content = coalesce($5, content),
+            tags = coalesce($6, tags)
+        where id = $1 and author_id = $2
+        returning *",
+        id,
+        user.id,
+        doc.name,
+        doc.title,
+        doc.content,
+        doc.tags.as_deref()
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    Ok(Json(template))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/template/{id}",
+    tag = "templates",
+    params(("id" = Uuid, Path, description = "Template id")),
+    responses(
+        (status = 200, description = "The deleted template", body = NoteTemplate),
+        (status = 404, description = "No such template")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn delete(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<NoteTemplate> {
+    let template = query_as!(
+        NoteTemplate,
+        "delete from note_template where id = $1 and author_id = $2 returning *",
+        id,
+        user.id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    Ok(Json(template))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notes/from-template/{id}",
+    tag = "templates",
+    params(("id" = Uuid, Path, description = "Template id"), FromTemplateQuery),
+    responses(
+        (status = 200, description = "The new note", body = Note),
+        (status = 404, description = "No such template")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn instantiate(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<FromTemplateQuery>,
+) -> JsonRes<Note> {
+    let template = query_as!(
+        NoteTemplate,
+        "select * from note_template where id = $1 and author_id = $2",
+        id,
+        user.id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(not_found)?;
+    let text = format!("{}\n{}", template.title, template.content);
+    let values = values(&app, &user, &query, &text).await;
+    let note = note::insert(
+        &app.db,
+        user.id,
+        &fill(&template.title, &values),
+        &fill(&template.content, &values),
+        &template.tags,
+        0,
+    )
+    .await?;
+    Ok(Json(note))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::StatusCode;
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::{fill, NoteTemplate};
+    use crate::{
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    #[test]
+    fn fills_known_placeholders() {
+        let values = HashMap::from([("date", "2025-01-02".to_owned())]);
+        assert_eq!(
+            fill("{{date}} / {{ date }} / {{unknown}} / {{date", &values),
+            "2025-01-02 / 2025-01-02 / {{unknown}} / {{date"
+        );
+    }
+
+    #[tokio::test]
+    async fn creates_notes_from_templates() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let res = app
+            .post_json(
+                "/templates",
+                "user_a",
+                json!({
+                    "name": "Meeting",
+                    "title": "Meeting {{date}}",
+                    "content": "By {{user.first_name}}. Weather: {{weather}}",
+                    "tags": ["meeting"]
+                }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let template: NoteTemplate = json(res).await;
+
+        let res = app
+            .post_json(
+                &format!("/notes/from-template/{}?lat=51.5&lon=-0.12", template.id),
+                "user_a",
+                json!({}),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let note: Note = json(res).await;
+        assert_eq!(
+            note.title,
+            format!("Meeting {}", Utc::now().format("%Y-%m-%d"))
+        );
+        assert_eq!(note.content, "By Test. Weather: 20°C in Testville");
+        assert_eq!(note.tags, ["meeting"]);
+
+        let note: Note = json(
+            app.post_json(
+                &format!("/notes/from-template/{}", template.id),
+                "user_a",
+                json!({}),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(note.content, "By Test. Weather: ");
+
+        let res = app
+            .post_json(
+                &format!("/notes/from-template/{}", template.id),
+                "user_b",
+                json!({}),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = app
+            .delete(&format!("/template/{}", template.id), "user_b")
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = app
+            .post_json(
+                &format!("/template/{}", template.id),
+                "user_a",
+                json!({ "name": "Standup" }),
+            )
+            .await;
+        let updated: NoteTemplate = json(res).await;
+        assert_eq!(updated.name, "Standup");
+        assert_eq!(updated.title, "Meeting {{date}}");
+    }
+}
@@ -0,0 +1,329 @@
+//! Runs the real router in-process against a throwaway Postgres schema, an
+//! in-memory bucket, fake auth and stubbed weather/geocoding servers.
+//!
+//! Tests that need the database are skipped unless `TEST_DATABASE_URL` points
+//! at a Postgres instance the tests may create schemas in, e.g.
+//!
+//! ```sh
+//! TEST_DATABASE_URL=postgres://postgres@localhost/editor_test cargo test
+//! ```
+//!
+//! On CI, where `CI` is set, a missing `TEST_DATABASE_URL` fails them instead,
+//! so they can't pass without having run.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use aws_config::Region;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, Method},
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgPoolOptions, query_scalar, Connection, Executor, PgConnection};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth, build_app, config::Config, jobs::Jobs, migrate::MIGRATOR, storage::Storage,
+    AppState,
+};
+
+/// Nothing listens on the discard port, so anything that reaches for a
+/// dependency fails fast instead of hanging the test.
+const UNREACHABLE: &str = "127.0.0.1:9";
+
+pub fn config(database_url: &str, stub_url: &str) -> Config {
+    Config {
+        bind_address: "127.0.0.1:0".to_owned(),
+        shutdown_timeout_secs: 1,
+        database_url: database_url.to_owned(),
+        auto_migrate: false,
+        db_max_connections: 5,
+        clerk_secret_key: String::new(),
+        svix_secret: "whsec_dGVzdC1zZWNyZXQ=".parse().unwrap(),
+        s3_url: format!("http://{}", UNREACHABLE),
+        s3_bucket: "editor".to_owned(),
+        r2_access_key_id: String::new(),
+        r2_access_key_secret: String::new(),
+        allow_origin: Vec::new(),
+        loc_token: "test".to_owned(),
+        weather_api_url: stub_url.to_owned(),
+        geocoding_api_url: stub_url.to_owned(),
+        metrics_token: "metrics".to_owned(),
+        trusted_proxies: Vec::new(),
+        collect_photos: false,
+    }
+}
+
+/// A state whose database and bucket are unreachable, for tests that only
+/// exercise routing and middleware.
+pub fn offline_state() -> AppState {
+    let config = config(
+        &format!("postgres://{}/editor", UNREACHABLE),
+        &format!("http://{}", UNREACHABLE),
+    );
+    let s3 = aws_sdk_s3::Config::builder()
+        .behavior_version_latest()
+        .endpoint_url(&config.s3_url)
+        .region(Region::new("auto"))
+        .credentials_provider(aws_sdk_s3::config::Credentials::new(
+            "test", "test", None, None, "test",
+        ))
+        .build();
+    AppState {
+        db: PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy(&config.database_url)
+            .unwrap(),
+        reqwest: reqwest::Client::new(),
+        storage: Storage::S3 {
+            client: aws_sdk_s3::Client::from_conf(s3),
+            bucket: config.s3_bucket.clone(),
+        },
+        auth: Auth::Fake,
+        users: Arc::default(),
+        jobs: Jobs::default(),
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+        rate_limiter: Arc::default(),
+        config: Arc::new(config),
+    }
+}
+
+#[derive(Default)]
+pub struct StubCalls {
+    pub forecast: AtomicUsize,
+    pub reverse: AtomicUsize,
+}
+
+impl StubCalls {
+    pub fn forecast(&self) -> usize {
+        self.forecast.load(Ordering::SeqCst)
+    }
+
+    pub fn reverse(&self) -> usize {
+        self.reverse.load(Ordering::SeqCst)
+    }
+}
+
+async fn forecast(State(calls): State<Arc<StubCalls>>) -> Json<serde_json::Value> {
+    calls.forecast.fetch_add(1, Ordering::SeqCst);
+    Json(json!({
+        "current": {
+            "time": "2025-03-01T12:00",
+            "temperature_2m": 20.0,
+            "wind_speed_10m": 5.5,
+            "weather_code": 3,
+            "relative_humidity_2m": 40.0,
+            "apparent_temperature": 19.0,
+            "precipitation_probability": 10.0
+        }
+    }))
+}
+
+async fn reverse(State(calls): State<Arc<StubCalls>>) -> Json<serde_json::Value> {
+    calls.reverse.fetch_add(1, Ordering::SeqCst);
+    Json(json!({ "address": { "city": "Testville" } }))
+}
+
+/// Serves canned Open-Meteo and LocationIQ responses on a random port.
+async fn spawn_stub() -> (String, Arc<StubCalls>) {
+    let calls = Arc::new(StubCalls::default());
+    let app = Router::new()
+        .route("/v1/forecast", get(forecast))
+        .route("/v1/reverse", get(reverse))
+        .with_state(calls.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, calls)
+}
+
+pub struct TestApp {
+    pub app: Router,
+    pub state: AppState,
+    pub stub: Arc<StubCalls>,
+    database_url: String,
+    schema: String,
+}
+
+impl TestApp {
+    /// Returns `None` when `TEST_DATABASE_URL` isn't set, in which case the
+    /// calling test should return early. Panics instead on CI.
+    pub async fn spawn() -> Option<TestApp> {
+        let Ok(database_url) = env::var("TEST_DATABASE_URL") else {
+            assert!(
+                env::var_os("CI").is_none(),
+                "TEST_DATABASE_URL has to be set on CI"
+            );
+            eprintln!("TEST_DATABASE_URL is not set, skipping");
+            return None;
+        };
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+        let mut conn = PgConnection::connect(&database_url).await.unwrap();
+        conn.execute(format!("create schema {}", schema).as_str())
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+        let search_path = format!("set search_path to {}", schema);
+        let db = PgPoolOptions::new()
+            .max_connections(5)
+            .after_connect(move |conn, _| {
+                let search_path = search_path.clone();
+                Box::pin(async move {
+                    conn.execute(search_path.as_str()).await?;
+                    Ok(())
+                })
+            })
+            .connect(&database_url)
+            .await
+            .unwrap();
+        MIGRATOR.run(&db).await.unwrap();
+        let (stub_url, stub) = spawn_stub().await;
+        let config = config(&database_url, &stub_url);
+        let state = AppState {
+            db,
+            reqwest: reqwest::Client::new(),
+            storage: Storage::Memory(Default::default()),
+            auth: Auth::Fake,
+            users: Arc::default(),
+            jobs: Jobs::default(),
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+            rate_limiter: Arc::default(),
+            config: Arc::new(config),
+        };
+        Some(TestApp {
+            app: build_app(state.clone()),
+            state,
+            stub,
+            database_url,
+            schema,
+        })
+    }
+
+    /// Inserts a user directly, as if Clerk's `user.created` webhook had run.
+    pub async fn create_user(&self, clerk_id: &str) -> Uuid {
+        query_scalar(
+            "insert into users (clerk_id, email, username, first_name, last_name, created_at, updated_at)
+             values ($1, $1 || '@example.com', $1, 'Test', 'User', now(), now()) returning id",
+        )
+        .bind(clerk_id)
+        .fetch_one(&self.state.db)
+        .await
+        .unwrap()
+    }
+
+    pub async fn send(&self, request: Request) -> Response {
+        self.app.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn get(&self, uri: &str, user: &str) -> Response {
+        self.send(request(Method::GET, uri, user).body(Body::empty()).unwrap())
+            .await
+    }
+
+    pub async fn post_json(&self, uri: &str, user: &str, body: impl Serialize) -> Response {
+        self.send(
+            request(Method::POST, uri, user)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap(),
+        )
+        .await
+    }
+
+    pub async fn patch_json(&self, uri: &str, user: &str, body: impl Serialize) -> Response {
+        self.send(
+            request(Method::PATCH, uri, user)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap(),
+        )
+        .await
+    }
+
+    /// Posts a single file field named `file`, like the web app's uploads.
+    pub async fn post_file(
+        &self,
+        uri: &str,
+        user: &str,
+        file_name: &str,
+        content_type: &str,
+        bytes: &[u8],
+    ) -> Response {
+        let boundary = "test-boundary";
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\nContent-Type: {content_type}\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        self.send(
+            request(Method::POST, uri, user)
+                .header(
+                    "content-type",
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+    }
+
+    pub async fn delete(&self, uri: &str, user: &str) -> Response {
+        self.send(
+            request(Method::DELETE, uri, user)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let database_url = self.database_url.clone();
+        let drop_schema = format!("drop schema if exists {} cascade", self.schema);
+        // Drop can't await, so clean up on a runtime of our own.
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    if let Ok(mut conn) = PgConnection::connect(&database_url).await {
+                        let _ = conn.execute(drop_schema.as_str()).await;
+                    }
+                })
+        })
+        .join()
+        .ok();
+    }
+}
+
+pub fn request(method: Method, uri: &str, user: &str) -> axum::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, format!("Bearer {}", user))
+}
+
+pub async fn body(response: Response) -> Bytes {
+    to_bytes(response.into_body(), usize::MAX).await.unwrap()
+}
+
+pub async fn json<T: DeserializeOwned>(response: Response) -> T {
+    serde_json::from_slice(&body(response).await).unwrap()
+}
@@ -0,0 +1,334 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, PgPool};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::{CurrentUser, Scope},
+    clerk::User,
+    error::{AppError, JsonRes},
+    AppState,
+};
+
+/// Personal access tokens start with this so `authenticate` can tell them
+/// apart from Clerk session JWTs.
+pub const TOKEN_PREFIX: &str = "edt_";
+const SECRET_LEN: usize = 40;
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiToken {
+    id: Uuid,
+    name: String,
+    /// The first few characters of the secret, to tell tokens apart.
+    prefix: String,
+    scopes: Vec<Scope>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Deserialize, ToSchema)]
+pub struct NewApiToken {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+/// Returned once on creation. Only a hash of `secret` is stored.
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CreatedApiToken {
+    token: ApiToken,
+    secret: String,
+}
+
+struct ApiTokenRow {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<ApiTokenRow> for ApiToken {
+    fn from(row: ApiTokenRow) -> Self {
+        ApiToken {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: parse_scopes(row.scopes),
+            last_used_at: row.last_used_at,
+            revoked_at: row.revoked_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// Scopes this build doesn't know about are dropped rather than granted.
+fn parse_scopes(scopes: Vec<String>) -> Vec<Scope> {
+    scopes
+        .iter()
+        .filter_map(|scope| Scope::from_str(scope).ok())
+        .collect()
+}
+
+/// A random alphanumeric string, long enough to be unguessable when `len`
+/// is 32 or more.
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Looks up the owner and scopes of a live token, marking it as used.
+pub async fn resolve(db: &PgPool, secret: &str) -> Result<Option<(User, Vec<Scope>)>, AppError> {
+    let row = query!(
+        r#"with token as (
+            update api_token set last_used_at = now()
+            where token_hash = $1 and revoked_at is null
+            returning user_id, scopes
+        )
+        select
+            token.scopes as "scopes!",
+            users.id,
+            users.clerk_id,
+            users.email,
+            users.username,
+            users.first_name,
+            users.last_name,
+            users.created_at,
+            users.updated_at
+        from token join users on users.id = token.user_id"#,
+        hash(secret)
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|row| {
+        let user = User {
+            id: row.id,
+            clerk_id: row.clerk_id,
+            username: row.username,
+            email: row.email,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        };
+        (user, parse_scopes(row.scopes))
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    tag = "tokens",
+    responses((status = 200, body = Vec<ApiToken>)),
+    security(("clerk" = []))
+)]
+pub async fn get_all(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<ApiToken>> {
+    let tokens = query_as!(
+        ApiTokenRow,
+        "select id, name, prefix, scopes, last_used_at, revoked_at, created_at
+        from api_token where user_id = $1 order by created_at desc",
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(tokens.into_iter().map(ApiToken::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body = NewApiToken,
+    responses(
+        (status = 200, body = CreatedApiToken),
+        (status = 400, description = "No scopes requested")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn create(
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(doc): Json<NewApiToken>,
+) -> JsonRes<CreatedApiToken> {
+    if doc.scopes.is_empty() {
+        return Err(AppError::WithStatus(
+            StatusCode::BAD_REQUEST,
+            anyhow::Error::msg("A token needs at least one scope"),
+        ));
+    }
+    let secret = format!("{}{}", TOKEN_PREFIX, random_string(SECRET_LEN));
+    let prefix = &secret[..TOKEN_PREFIX.len() + 4];
+    let scopes: Vec<String> = doc.scopes.iter().map(Scope::to_string).collect();
+    let token = query_as!(
+        ApiTokenRow,
+        "insert into api_token (user_id, name, prefix, token_hash, scopes)
+        values ($1, $2, $3, $4, $5)
+        returning id, name, prefix, scopes, last_used_at, revoked_at, created_at",
+        user.id,
+        doc.name,
+        prefix,
+        hash(&secret),
+        &scopes,
+    )
+    .fetch_one(&app.db)
+    .await?;
+    Ok(Json(CreatedApiToken {
+        token: token.into(),
+        secret,
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/token/{id}",
+    tag = "tokens",
+    params(("id" = Uuid, Path, description = "Token id")),
+    responses(
+        (status = 200, description = "The revoked token", body = ApiToken),
+        (status = 404, description = "No live token with this id")
+    ),
+    security(("clerk" = []))
+)]
+pub async fn revoke(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<ApiToken> {
+    let token = query_as!(
+        ApiTokenRow,
+        "update api_token set revoked_at = now()
+        where id = $1 and user_id = $2 and revoked_at is null
+        returning id, name, prefix, scopes, last_used_at, revoked_at, created_at",
+        id,
+        user.id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or_else(|| {
+        AppError::WithStatus(StatusCode::NOT_FOUND, anyhow::Error::msg("No such token"))
+    })?;
+    Ok(Json(token.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::{ApiToken, CreatedApiToken};
+    use crate::{
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    async fn create(app: &TestApp, scopes: &[&str]) -> CreatedApiToken {
+        let res = app
+            .post_json(
+                "/tokens",
+                "user_a",
+                json!({ "name": "cli", "scopes": scopes }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        json(res).await
+    }
+
+    #[tokio::test]
+    async fn tokens_act_as_their_owner() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let created = create(&app, &["notes:write"]).await;
+        assert!(created.secret.starts_with(&created.token.prefix));
+
+        let res = app
+            .post_json(
+                "/notes",
+                &created.secret,
+                json!({ "title": "From a script", "content": "" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let notes: Vec<Note> = json(app.get("/notes", "user_a").await).await;
+        assert_eq!(notes.len(), 1);
+
+        let tokens: Vec<ApiToken> = json(app.get("/tokens", "user_a").await).await;
+        assert!(tokens[0].last_used_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn scopes_are_enforced() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let created = create(&app, &["notes:read"]).await;
+
+        let res = app.get("/notes", &created.secret).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .post_json(
+                "/notes",
+                &created.secret,
+                json!({ "title": "", "content": "" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.get("/photos", &created.secret).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.get("/tokens", &created.secret).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let created = create(&app, &["notes:read"]).await;
+
+        let res = app
+            .delete(&format!("/token/{}", created.token.id), "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.get("/notes", &created.secret).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = app
+            .delete(&format!("/token/{}", created.token.id), "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = app.get("/notes", "edt_notarealtoken").await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,22 +1,25 @@
-use crate::error::JsonRes;
+use crate::error::{AppError, JsonRes};
 use axum::{
     extract::{Query, State},
     Json,
 };
 use chrono::{DateTime, Duration, Utc};
+use metrics::counter;
 use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as};
+use strum_macros::{Display, EnumString};
 use ts_rs::TS;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::AppState;
 
-#[derive(TS, Deserialize, Serialize, PartialEq)]
+#[derive(TS, Clone, Copy, Deserialize, Serialize, PartialEq, ToSchema, Display, EnumString)]
 pub enum TemperatureUnit {
     C,
     F,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 pub struct WeatherQuery {
     pub lat: f64,
     pub lon: f64,
@@ -54,7 +57,7 @@ struct LocationResponse {
 
 #[derive(TS)]
 #[ts(export)]
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
 pub struct Weather {
     pub id: String,
     pub location: String,
@@ -68,10 +71,51 @@ pub struct Weather {
     pub updated_at: DateTime<Utc>,
 }
 
+/// Rounded to two decimals.
+fn fahrenheit(celsius: f64) -> f64 {
+    ((celsius * (9. / 5.) + 32.) * 100.).round() / 100.
+}
+
+impl Weather {
+    /// Weather is fetched and cached in Celsius, and only converted on the
+    /// way out.
+    fn in_unit(mut self, unit: TemperatureUnit) -> Self {
+        if unit == TemperatureUnit::F {
+            self.temperature_2m = fahrenheit(self.temperature_2m);
+            self.apparent_temperature = fahrenheit(self.apparent_temperature);
+        }
+        self
+    }
+
+    /// A short line such as `20°C in London`. `unit` is the one the weather
+    /// was looked up in.
+    pub fn summary(&self, unit: TemperatureUnit) -> String {
+        format!("{}°{} in {}", self.temperature_2m, unit, self.location)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/weather",
+    tag = "weather",
+    params(WeatherQuery),
+    responses((status = 200, body = Weather)),
+    security(("clerk" = []))
+)]
 pub async fn get(
     State(app): State<AppState>,
     Query(query): Query<WeatherQuery>,
 ) -> JsonRes<Weather> {
+    Ok(Json(current(&app, &query).await?))
+}
+
+/// The weather at a place in the unit asked for, looked up at most once a
+/// minute.
+pub async fn current(app: &AppState, query: &WeatherQuery) -> Result<Weather, AppError> {
+    Ok(celsius(app, query).await?.in_unit(query.unit))
+}
+
+async fn celsius(app: &AppState, query: &WeatherQuery) -> Result<Weather, AppError> {
     let id = format!("({:.2},{:.2})", query.lat, query.lon);
     query!("delete from weather where created_at < (now() - INTERVAL '1 min')")
         .execute(&app.db)
@@ -89,12 +133,15 @@ pub async fn get(
         })
         .unwrap_or(None);
     if let Some(weather) = weather {
-        Ok(Json(weather))
+        counter!("weather_cache_hits_total").increment(1);
+        Ok(weather)
     } else {
+        counter!("weather_cache_misses_total").increment(1);
         let res = app
         .reqwest
         .get(format!(
-                "https://api.open-meteo.com/v1/forecast?latitude={}&longitude={}&current=temperature_2m,wind_speed_10m,relative_humidity_2m,apparent_temperature,precipitation_probability,weather_code&temperature_unit=celsius",
+                "{}/v1/forecast?latitude={}&longitude={}&current=temperature_2m,wind_speed_10m,relative_humidity_2m,apparent_temperature,precipitation_probability,weather_code&temperature_unit=celsius",
+            app.config.weather_api_url,
             query.lat,
             query.lon,
         ))
@@ -105,10 +152,8 @@ pub async fn get(
         let location = app
             .reqwest
             .get(format!(
-                "https://us1.locationiq.com/v1/reverse?key={}&lat={}&lon={}&format=json",
-                env_var!("LOC_TOKEN"),
-                query.lat,
-                query.lon,
+                "{}/v1/reverse?key={}&lat={}&lon={}&format=json",
+                app.config.geocoding_api_url, app.config.loc_token, query.lat, query.lon,
             ))
             .send()
             .await?
@@ -120,7 +165,7 @@ pub async fn get(
                 .county
                 .unwrap_or("unknown location".to_owned()),
         );
-        let mut weather = query_as!(
+        let weather = query_as!(
             Weather,
             "insert into weather (
                id,
@@ -143,10 +188,62 @@ pub async fn get(
         )
         .fetch_one(&app.db)
         .await?;
-        if query.unit == TemperatureUnit::F {
-            let f = weather.temperature_2m * (9. / 5.) + 32.;
-            weather.temperature_2m = (f * 100.).round() / 100.
-        }
-        Ok(Json(weather))
+        Ok(weather)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::{TemperatureUnit, Weather};
+    use crate::testing::{json, TestApp};
+
+    #[tokio::test]
+    async fn caches_lookups() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let res = app
+            .get("/weather?lat=51.5&lon=-0.12&unit=C", "user_a")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let weather: Weather = json(res).await;
+        assert_eq!(weather.location, "Testville");
+        assert_eq!(weather.temperature_2m, 20.);
+
+        let _: Weather = json(
+            app.get("/weather?lat=51.5&lon=-0.12&unit=C", "user_a")
+                .await,
+        )
+        .await;
+        assert_eq!(app.stub.forecast(), 1);
+        assert_eq!(app.stub.reverse(), 1);
+    }
+
+    #[tokio::test]
+    async fn converts_to_fahrenheit() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let weather: Weather = json(app.get("/weather?lat=10&lon=10&unit=F", "user_a").await).await;
+        assert_eq!(weather.temperature_2m, 68.);
+        assert_eq!(weather.apparent_temperature, 66.2);
+    }
+
+    #[tokio::test]
+    async fn converts_cached_weather() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        let weather: Weather = json(app.get("/weather?lat=10&lon=10&unit=C", "user_a").await).await;
+        assert_eq!(weather.temperature_2m, 20.);
+        let weather: Weather = json(app.get("/weather?lat=10&lon=10&unit=F", "user_a").await).await;
+        assert_eq!(app.stub.forecast(), 1);
+        assert_eq!(weather.temperature_2m, 68.);
+        assert_eq!(weather.summary(TemperatureUnit::F), "68°F in Testville");
     }
 }
@@ -0,0 +1,312 @@
+//! Links between notes, written as `[[Note Title]]` or as a link to
+//! `/note/<id>`. They are stored whenever a note is saved, so a note can list
+//! the notes that link to it.
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgPool};
+use ts_rs::TS;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::CurrentUser,
+    error::{AppError, JsonRes},
+    note,
+    render::{references, replace_spans},
+    share::{self, Access},
+    AppState,
+};
+
+#[derive(TS)]
+#[ts(export)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NoteLink {
+    pub id: Uuid,
+    pub title: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The title a wiki link points at, without the heading in `[[Title#Heading]]`.
+fn wiki_title(dest: &str) -> &str {
+    dest.split('#').next().unwrap_or(dest).trim()
+}
+
+fn linked_id(dest: &str) -> Option<Uuid> {
+    let path = dest.split(['#', '?']).next()?;
+    let id = path.strip_prefix("/note/")?.trim_end_matches('/');
+    Uuid::parse_str(id).ok()
+}
+
+/// Records the notes `content` links to, replacing what was recorded before.
+/// Wiki links match the titles of the author's notes, ignoring case.
+pub async fn sync(
+    db: &PgPool,
+    note_id: Uuid,
+    author_id: Uuid,
+    content: &str,
+) -> Result<(), AppError> {
+    let mut ids = Vec::new();
+    let mut titles = Vec::new();
+    for reference in references(content).iter().filter(|r| !r.image) {
+        if reference.wiki {
+            titles.push(wiki_title(&reference.dest).to_lowercase());
+        } else if let Some(id) = linked_id(&reference.dest) {
+            ids.push(id);
+        }
+    }
+    let mut tx = db.begin().await?;
+    query!("delete from note_link where source_id = $1", note_id)
+        .execute(&mut *tx)
+        .await?;
+    query!(
+        "insert into note_link (source_id, target_id, by_title)
+        select $1, id, author_id = $3 and lower(title) = any($4) from note
+        where id <> $1 and (id = any($2) or (author_id = $3 and lower(title) = any($4)))",
+        note_id,
+        &ids,
+        author_id,
+        &titles
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// `content` with wiki links to `old` pointing at `new` instead. Headings and
+/// labels are kept.
+fn rename(content: &str, old: &str, new: &str) -> String {
+    let old = old.trim().to_lowercase();
+    let replacements = references(content)
+        .into_iter()
+        .filter(|r| r.wiki && !r.image && wiki_title(&r.dest).to_lowercase() == old)
+        .map(|r| {
+            let inner = &content[r.span.start + 2..r.span.end - 2];
+            let rest = inner.find(['#', '|']).map_or("", |i| &inner[i..]);
+            (r.span, format!("[[{}{}]]", new, rest))
+        })
+        .collect();
+    replace_spans(content, replacements)
+}
+
+/// Rewrites wiki links in notes linking to a note whose title changed. Only
+/// the author's own notes can link to it by title, so notes of anyone else
+/// that link to it by address are left alone.
+pub async fn retitle(db: &PgPool, note_id: Uuid, old: &str, new: &str) -> Result<(), AppError> {
+    if old.trim().to_lowercase() == new.trim().to_lowercase() {
+        return Ok(());
+    }
+    let sources = query!(
+        "select source.id, source.content
+        from note_link
+        join note source on source.id = note_link.source_id
+        join note target on target.id = note_link.target_id
+        where note_link.target_id = $1 and note_link.by_title
+          and source.author_id = target.author_id",
+        note_id
+    )
+    .fetch_all(db)
+    .await?;
+    for source in sources {
+        let content = rename(&source.content, old, new);
+        if content != source.content {
+            note::set_content(db, source.id, &content, None).await?;
+        }
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/backlinks",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "Notes you can see that link to this one", body = Vec<NoteLink>)),
+    security(("clerk" = []))
+)]
+pub async fn backlinks(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<NoteLink>> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let notes = query_as!(
+        NoteLink,
+        "select note.id, note.title, note.updated_at
+        from note_link join note on note.id = note_link.source_id
+        where note_link.target_id = $1 and (note.author_id = $2 or exists (
+            select 1 from note_share where note_share.note_id = note.id and note_share.user_id = $2
+        ))
+        order by note.updated_at desc",
+        id,
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(notes))
+}
+
+#[utoipa::path(
+    get,
+    path = "/note/{id}/links",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note id")),
+    responses((status = 200, description = "Notes you can see that this one links to", body = Vec<NoteLink>)),
+    security(("clerk" = []))
+)]
+pub async fn outgoing(
+    Path(id): Path<Uuid>,
+    State(app): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> JsonRes<Vec<NoteLink>> {
+    share::require(&app.db, id, user.id, Access::Viewer).await?;
+    let notes = query_as!(
+        NoteLink,
+        "select note.id, note.title, note.updated_at
+        from note_link join note on note.id = note_link.target_id
+        where note_link.source_id = $1 and (note.author_id = $2 or exists (
+            select 1 from note_share where note_share.note_id = note.id and note_share.user_id = $2
+        ))
+        order by note.title",
+        id,
+        user.id
+    )
+    .fetch_all(&app.db)
+    .await?;
+    Ok(Json(notes))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::{rename, NoteLink};
+    use crate::{
+        note::Note,
+        testing::{json, TestApp},
+    };
+
+    #[test]
+    fn renames_wiki_links() {
+        assert_eq!(
+            rename(
+                "[[Plan]], [[plan#Goals|goals]], [[Planning]] and `[[Plan]]`",
+                "Plan",
+                "Roadmap"
+            ),
+            "[[Roadmap]], [[Roadmap#Goals|goals]], [[Planning]] and `[[Plan]]`"
+        );
+    }
+
+    async fn create(app: &TestApp, user: &str, title: &str, content: &str) -> Note {
+        json(
+            app.post_json(
+                "/notes",
+                user,
+                json!({ "title": title, "content": content }),
+            )
+            .await,
+        )
+        .await
+    }
+
+    async fn links(app: &TestApp, user: &str, uri: String) -> Vec<String> {
+        let res = app.get(&uri, user).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let notes: Vec<NoteLink> = json(res).await;
+        notes.into_iter().map(|note| note.title).collect()
+    }
+
+    #[tokio::test]
+    async fn tracks_links_and_backlinks() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let plan = create(&app, "user_a", "Plan", "").await;
+        let other = create(&app, "user_b", "Elsewhere", "").await;
+        let meeting = create(
+            &app,
+            "user_a",
+            "Meeting",
+            &format!(
+                "See [[plan#Goals|the plan]] and [this](/note/{}) and [[Missing]].",
+                other.id
+            ),
+        )
+        .await;
+
+        let outgoing = format!("/note/{}/links", meeting.id);
+        assert_eq!(links(&app, "user_a", outgoing.clone()).await, ["Plan"]);
+        let backlinks = format!("/note/{}/backlinks", plan.id);
+        assert_eq!(links(&app, "user_a", backlinks.clone()).await, ["Meeting"]);
+        // user_b can't see the meeting note that links to theirs.
+        let res = app
+            .get(&format!("/note/{}/backlinks", other.id), "user_b")
+            .await;
+        assert!(json::<Vec<NoteLink>>(res).await.is_empty());
+
+        app.post_json(
+            &format!("/note/{}", plan.id),
+            "user_a",
+            json!({ "title": "Roadmap" }),
+        )
+        .await;
+        let meeting: Note = json(app.get(&format!("/note/{}", meeting.id), "user_a").await).await;
+        assert!(meeting
+            .content
+            .starts_with("See [[Roadmap#Goals|the plan]] and"));
+        assert_eq!(links(&app, "user_a", outgoing.clone()).await, ["Roadmap"]);
+
+        app.post_json(
+            &format!("/note/{}", meeting.id),
+            "user_a",
+            json!({ "content": "Nothing" }),
+        )
+        .await;
+        assert!(links(&app, "user_a", backlinks).await.is_empty());
+        assert!(links(&app, "user_a", outgoing).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retitling_leaves_other_users_notes_alone() {
+        let Some(app) = TestApp::spawn().await else {
+            return;
+        };
+        app.create_user("user_a").await;
+        app.create_user("user_b").await;
+        let plan = create(&app, "user_a", "Plan", "").await;
+        create(&app, "user_b", "Plan", "").await;
+        // user_b links to user_a's note by address, and to their own Plan by
+        // title.
+        let theirs = create(
+            &app,
+            "user_b",
+            "Notes",
+            &format!("[Their plan](/note/{}) and [[Plan]]", plan.id),
+        )
+        .await;
+        let mine = create(&app, "user_a", "Meeting", "See [[Plan]]").await;
+
+        app.post_json(
+            &format!("/note/{}", plan.id),
+            "user_a",
+            json!({ "title": "Roadmap" }),
+        )
+        .await;
+        let mine: Note = json(app.get(&format!("/note/{}", mine.id), "user_a").await).await;
+        assert_eq!(mine.content, "See [[Roadmap]]");
+        let theirs: Note = json(app.get(&format!("/note/{}", theirs.id), "user_b").await).await;
+        assert_eq!(
+            theirs.content,
+            format!("[Their plan](/note/{}) and [[Plan]]", plan.id)
+        );
+    }
+}
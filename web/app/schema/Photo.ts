@@ -7,4 +7,9 @@ export type Photo = {
   size_b: bigint;
   created_at: string;
   updated_at: string;
+  /**
+   * When a note first embedded the photo. Photos never embedded are only
+   * in the gallery, and aren't collected.
+   */
+  referenced_at: string | null;
 };